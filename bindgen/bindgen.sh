@@ -13,11 +13,13 @@ esac
 
 cat > src/${FFI_RS} << HERE
 #![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
 #![allow(non_upper_case_globals)]
 #![allow(unused)]
 HERE
 
 bindgen --allowlist-type 'md_ioctl' \
+	--allowlist-type 'diocgattr_arg' \
 	--allowlist-item 'MDIOVERSION' \
 	--allowlist-item 'MDNPAD' \
 	--allowlist-item 'MD_ASYNC' \
@@ -33,11 +35,3 @@ bindgen --allowlist-type 'md_ioctl' \
 	--allowlist-item 'MD_VERIFY' \
 	${CRATEDIR}/bindgen/wrapper.h >> ${CRATEDIR}/src/${FFI_RS}
 rustfmt ${CRATEDIR}/src/${FFI_RS}
-
-cat > tests/functional/${FFI_RS} << HERE
-#![allow(non_camel_case_types)]
-#![allow(non_snake_case)]
-HERE
-bindgen --allowlist-type diocgattr_arg \
-	/usr/include/sys/disk.h >> ${CRATEDIR}/tests/functional/${FFI_RS}
-rustfmt ${CRATEDIR}/tests/functional/${FFI_RS}
@@ -1,2 +1,3 @@
 #include <sys/types.h>
+#include <sys/disk.h>
 #include <sys/mdioctl.h>
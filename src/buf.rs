@@ -0,0 +1,82 @@
+//! Sector-aligned memory
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+    slice,
+};
+
+/// A zero-initialized heap buffer whose address is aligned to a sector boundary.
+///
+/// The md driver itself only requires that I/O lengths and offsets be multiples of the sectorsize,
+/// but other GEOM classes stacked above it may also require aligned memory.  Allocate one with
+/// [`MdDisk::alloc_sectors`](crate::MdDisk::alloc_sectors) to get both right.
+///
+/// # Example
+/// ```
+/// let mut buf = mdconfig::AlignedBuf::new(8192, 4096);
+/// assert_eq!(buf.as_ptr() as usize % 4096, 0);
+/// buf[..4].copy_from_slice(b"BOOT");
+/// ```
+pub struct AlignedBuf {
+    ptr:    NonNull<u8>,
+    len:    usize,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    /// Allocate `len` zeroed bytes, aligned to `align` bytes.
+    ///
+    /// # Panics
+    ///
+    /// If `align` is not a power of two, or if `len` rounded up to `align` overflows `isize`.
+    pub fn new(len: usize, align: usize) -> Self {
+        // Zero-sized allocations aren't allowed, so always allocate at least one aligned block.
+        let layout = Layout::from_size_align(len.max(align), align)
+            .expect("invalid alignment for AlignedBuf");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(ptr) = NonNull::new(ptr) else {
+            alloc::handle_alloc_error(layout);
+        };
+        AlignedBuf { ptr, len, layout }
+    }
+
+    /// The alignment of the buffer's address, in bytes.
+    pub fn align(&self) -> usize {
+        self.layout.align()
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl fmt::Debug for AlignedBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlignedBuf")
+            .field("len", &self.len)
+            .field("align", &self.align())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+// Safe because AlignedBuf uniquely owns its allocation, like a Box<[u8]>.
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
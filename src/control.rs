@@ -0,0 +1,208 @@
+//! A reusable handle to the md control device
+use std::{
+    fs,
+    io,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+    path::Path,
+    ptr,
+    sync::Arc,
+};
+
+use super::{Builder, Md, MdError, MdInfo, Operation, ffi, ioctl};
+
+/// An `md_ioctl` structure with everything but the unit number zeroed.
+fn mdio(unit: u32) -> ffi::md_ioctl {
+    ffi::md_ioctl {
+        md_version:    ffi::MDIOVERSION,
+        md_unit:       unit,
+        md_type:       0,
+        md_file:       ptr::null_mut(),
+        md_mediasize:  0,
+        md_sectorsize: 0,
+        md_options:    0,
+        md_base:       0,
+        md_fwheads:    0,
+        md_fwsectors:  0,
+        md_label:      ptr::null_mut(),
+        md_pad:        [0; ffi::MDNPAD as usize],
+    }
+}
+
+/// An open handle to the md control device, `/dev/mdctl`.
+///
+/// Every operation on an md device is performed through the control device.  By default, this
+/// crate opens it anew for each operation.  An `MdControl` can be used instead to open it just
+/// once.  That saves a syscall per operation, and more importantly it allows md devices to be
+/// managed from within a [capsicum(4)](https://man.freebsd.org/cgi/man.cgi?query=capsicum)
+/// sandbox or a chroot, where `/dev/mdctl` cannot be opened.
+///
+/// # Example
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use mdconfig::{Builder, MdControl};
+/// let ctl = Arc::new(MdControl::open().unwrap());
+/// let md = ctl.create(Builder::swap(1 << 20)).unwrap();
+/// // Enter capability mode here.  `md` can still be resized and detached.
+/// md.resize(1 << 21, false).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct MdControl {
+    fd: OwnedFd,
+}
+
+impl MdControl {
+    /// Open `/dev/mdctl`.
+    pub fn open() -> Result<Self, MdError> {
+        MdControl::open_path(Path::new("/dev/mdctl"))
+    }
+
+    /// Open the md control device at a nonstandard path, such as within a chroot.
+    pub fn open_path(path: &Path) -> Result<Self, MdError> {
+        let f = fs::File::open(path)
+            .map_err(|e| MdError::new(Operation::Open, None, Some(path), e))?;
+        Ok(MdControl { fd: f.into() })
+    }
+
+    /// Attach a new device, using a fully prepared `md_ioctl` structure.  Returns its unit number.
+    pub(crate) fn attach(
+        &self,
+        mdio: &mut ffi::md_ioctl,
+        unit: Option<u32>,
+        path: Option<&Path>,
+    ) -> Result<u32, MdError> {
+        unsafe { ioctl::mdiocattach(self.fd.as_raw_fd(), mdio) }.map_err(
+            |e| MdError::new(Operation::Attach, unit, path, e.into()),
+        )?;
+        Ok(mdio.md_unit)
+    }
+
+    /// Finalize a [`Builder`] into an [`Md`] device.
+    ///
+    /// Unlike [`Builder::create`], the resulting `Md` will retain a reference to this
+    /// `MdControl`, and use it for all further operations, including detaching on Drop.
+    pub fn create(self: &Arc<Self>, builder: Builder) -> Result<Md, MdError> {
+        builder.validate()?;
+        builder.attach(self, Some(self.clone()))
+    }
+
+    /// Detach an md device, by unit number.
+    ///
+    /// Without `force`, this will fail with [`MdError::Busy`] if the device is open.
+    pub fn detach(&self, unit: u32, force: bool) -> Result<(), MdError> {
+        let mut mdio = mdio(unit);
+        if force {
+            mdio.md_options |= ffi::MD_FORCE;
+        }
+        unsafe { ioctl::mdiocdetach(self.fd.as_raw_fd(), &mut mdio) }.map_err(
+            |e| MdError::new(Operation::Detach, Some(unit), None, e.into()),
+        )?;
+        Ok(())
+    }
+
+    /// List the unit numbers of every md device attached to the system, in ascending order.
+    ///
+    /// `MDIOCLIST` can report only a limited number of devices.  If there are more than that, this
+    /// fails rather than opening any paths, so that it's safe to use within a capsicum sandbox.
+    /// [`list`](crate::list) instead falls back to scanning `/dev`.
+    pub fn list(&self) -> Result<Vec<u32>, MdError> {
+        let (units, truncated) = self.list_ioctl()?;
+        if truncated {
+            let e = io::Error::other("too many md devices for MDIOCLIST");
+            return Err(MdError::new(Operation::List, None, None, e));
+        }
+        Ok(units)
+    }
+
+    /// List units with `MDIOCLIST`.  Also reports whether the list was truncated.
+    pub(crate) fn list_ioctl(&self) -> Result<(Vec<u32>, bool), MdError> {
+        let mut mdio = mdio(0);
+        unsafe { ioctl::mdioclist(self.fd.as_raw_fd(), &mut mdio) }
+            .map_err(|e| MdError::new(Operation::List, None, None, e.into()))?;
+        // The kernel stores the number of units in md_pad[0] and the units themselves in the
+        // following slots.  If there are too many to fit, it stores -1 in the final slot.
+        let count = mdio.md_pad[0] as usize;
+        let mut units = mdio.md_pad[1..=count]
+            .iter()
+            .map(|u| *u as u32)
+            .collect::<Vec<_>>();
+        units.sort_unstable();
+        Ok((units, mdio.md_pad[ffi::MDNPAD as usize - 1] == -1))
+    }
+
+    /// Like [`MdControl::list`], but if `MDIOCLIST` is truncated, scan `/dev` for the remainder.
+    pub(crate) fn list_all(&self) -> Result<Vec<u32>, MdError> {
+        let (mut units, truncated) = self.list_ioctl()?;
+        if truncated {
+            let list_err =
+                |e: io::Error| MdError::new(Operation::List, None, None, e);
+            for entry in fs::read_dir("/dev").map_err(list_err)? {
+                let name = entry.map_err(list_err)?.file_name();
+                let unit = name
+                    .to_str()
+                    .and_then(|n| n.strip_prefix("md"))
+                    .and_then(|n| n.parse::<u32>().ok());
+                if let Some(unit) = unit {
+                    units.push(unit);
+                }
+            }
+            units.sort_unstable();
+            units.dedup();
+        }
+        Ok(units)
+    }
+
+    /// Query the kernel for information about an existing md device.
+    ///
+    /// See [`query`](crate::query).
+    pub fn query(&self, unit: u32) -> Result<MdInfo, MdError> {
+        let mut file = vec![0u8; libc::PATH_MAX as usize];
+        let mut label = vec![0u8; libc::PATH_MAX as usize];
+        let mut mdio = mdio(unit);
+        mdio.md_file = file.as_mut_ptr() as *mut libc::c_char;
+        mdio.md_label = label.as_mut_ptr() as *mut libc::c_char;
+        unsafe { ioctl::mdiocquery(self.fd.as_raw_fd(), &mut mdio) }.map_err(
+            |e| MdError::new(Operation::Query, Some(unit), None, e.into()),
+        )?;
+        MdInfo::from_mdio(&mdio, &file, &label)
+    }
+
+    /// Change an md device's size in bytes.
+    ///
+    /// See [`Md::resize`].
+    pub fn resize(
+        &self,
+        unit: u32,
+        newsize: libc::off_t,
+        force: bool,
+    ) -> Result<(), MdError> {
+        let mut mdio = mdio(unit);
+        mdio.md_mediasize = newsize;
+        if force {
+            mdio.md_options |= ffi::MD_FORCE;
+        }
+        unsafe { ioctl::mdiocresize(self.fd.as_raw_fd(), &mut mdio) }.map_err(
+            |e| MdError::new(Operation::Resize, Some(unit), None, e.into()),
+        )?;
+        Ok(())
+    }
+}
+
+impl AsFd for MdControl {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+/// Adopt an already open descriptor for the md control device, such as one inherited from a
+/// parent process.
+impl From<OwnedFd> for MdControl {
+    fn from(fd: OwnedFd) -> Self {
+        MdControl { fd }
+    }
+}
+
+impl From<MdControl> for OwnedFd {
+    fn from(ctl: MdControl) -> Self {
+        ctl.fd
+    }
+}
@@ -0,0 +1,484 @@
+//! Opened md devices
+use std::{
+    fs,
+    io::{self, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write},
+    mem,
+    ops::Range,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd},
+        unix::fs::FileExt,
+    },
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use super::{AlignedBuf, Md, MdError, Operation, cstr_bytes, ffi, ioctl};
+
+/// Maximum length of a disk's ident, including the terminating NUL.
+pub(crate) const DISK_IDENT_SIZE: usize = 256;
+/// Maximum number of buffers passed to a single `preadv` or `pwritev`.
+const IOV_MAX: usize = 1024;
+
+/// An open md device, obtained from [`Md::open`].
+///
+/// It can be read and written like any other file, and also provides access to the disk ioctls.
+/// Note that it doesn't keep the device attached; if the `Md` is dropped, further operations will
+/// fail.
+///
+/// The bounds checks done by [`MdDisk::read_sectors`] and friends use a cached copy of the
+/// mediasize, taken from [`Md::size`] when the device is opened.  It's refreshed with
+/// `DIOCGMEDIASIZE` whenever a transfer appears to extend beyond it, or fails with `EINVAL` or
+/// `ENXIO`, so resizes by other processes are eventually noticed.
+///
+/// # Example
+/// ```no_run
+/// let md = mdconfig::Builder::malloc(1 << 20).sectorsize(4096).create().unwrap();
+/// let disk = md.open(std::fs::OpenOptions::new().read(true)).unwrap();
+/// assert_eq!(disk.sectorsize().unwrap(), 4096);
+/// ```
+#[derive(Debug)]
+pub struct MdDisk {
+    file:       fs::File,
+    unit:       u32,
+    sectorsize: u32,
+    /// Last known mediasize, for bounds checks.
+    mediasize:  AtomicU64,
+}
+
+impl MdDisk {
+    pub(crate) fn open(
+        md: &Md,
+        opts: &fs::OpenOptions,
+    ) -> Result<Self, MdError> {
+        let file = opts.open(md.path()).map_err(|e| {
+            MdError::new(
+                Operation::OpenDisk,
+                Some(md.unit()),
+                Some(md.path()),
+                e,
+            )
+        })?;
+        Ok(MdDisk {
+            file,
+            unit: md.unit(),
+            sectorsize: md.sectorsize(),
+            mediasize: AtomicU64::new(md.size()),
+        })
+    }
+
+    fn io_err(&self, op: Operation, e: io::Error) -> MdError {
+        // The device may have been resized since the bounds were checked.
+        if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENXIO)) {
+            let _ = self.mediasize();
+        }
+        MdError::new(op, Some(self.unit), None, e)
+    }
+
+    fn ioctl_err(&self, name: &'static str, e: nix::Error) -> MdError {
+        MdError::new(
+            Operation::DiskIoctl(name),
+            Some(self.unit),
+            None,
+            e.into(),
+        )
+    }
+
+    /// Allocate a zeroed buffer of `count` sectors, aligned to the device's sectorsize.
+    ///
+    /// # Panics
+    ///
+    /// If `count` sectors would overflow `usize`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::fs::OpenOptions;
+    /// let md = mdconfig::Builder::malloc(1 << 20).sectorsize(2048).create().unwrap();
+    /// let disk = md.open(OpenOptions::new().read(true)).unwrap();
+    /// let mut buf = disk.alloc_sectors(4);
+    /// disk.read_sectors(0, &mut buf).unwrap();
+    /// ```
+    pub fn alloc_sectors(&self, count: usize) -> AlignedBuf {
+        let sectorsize = self.sectorsize as usize;
+        let len = count
+            .checked_mul(sectorsize)
+            .expect("sector count overflows usize");
+        AlignedBuf::new(len, sectorsize)
+    }
+
+    /// Look up a GEOM attribute of type `int`, like "MNT::verified", with `DIOCGATTR`.
+    pub fn attr_int(&self, name: &str) -> Result<i32, MdError> {
+        let arg = self.attr(name, mem::size_of::<libc::c_int>())?;
+        Ok(unsafe { arg.value.i })
+    }
+
+    /// Look up a GEOM attribute of type `off_t`, like "GEOM::candelete", with `DIOCGATTR`.
+    pub fn attr_off(&self, name: &str) -> Result<i64, MdError> {
+        let arg = self.attr(name, mem::size_of::<libc::off_t>())?;
+        Ok(unsafe { arg.value.off })
+    }
+
+    /// Look up a GEOM attribute of type string, like "GEOM::ident", with `DIOCGATTR`.
+    pub fn attr_str(&self, name: &str) -> Result<String, MdError> {
+        let len = mem::size_of::<ffi::diocgattr_arg__bindgen_ty_1>();
+        let arg = self.attr(name, len)?;
+        let value = unsafe { arg.value.str_ }.map(|c| c as u8);
+        Ok(String::from_utf8_lossy(cstr_bytes(&value)).into_owned())
+    }
+
+    /// Delete a range of bytes, with `BIO_DELETE`.
+    ///
+    /// This is the equivalent of TRIM for an md device.  Malloc and swap-backed devices free the
+    /// underlying memory, and vnode-backed devices punch a hole in their backing file.  If the file
+    /// system can't punch holes, the range is zero-filled instead, or the request fails if the
+    /// device was created with [`Builder::mustdealloc`](crate::Builder::mustdealloc).  Either way,
+    /// the deleted range reads back as zeros.  Use [`Md::backing_allocated`] to check how much
+    /// storage was actually freed.
+    ///
+    /// Both ends of the range must be multiples of the sectorsize, and it must not extend beyond
+    /// the end of the device.  Otherwise, this fails with [`MdError::Misaligned`] or
+    /// [`MdError::OutOfRange`] without issuing the ioctl.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::fs::OpenOptions;
+    /// let md = mdconfig::Builder::malloc(1 << 20).create().unwrap();
+    /// let disk = md.open(OpenOptions::new().write(true)).unwrap();
+    /// disk.delete(0..1 << 19).unwrap();
+    /// ```
+    pub fn delete(&self, range: Range<u64>) -> Result<(), MdError> {
+        const OP: Operation = Operation::DiskIoctl("DIOCGDELETE");
+        let sectorsize = u64::from(self.sectorsize()?);
+        let mediasize = self.mediasize()?;
+        if range.start % sectorsize != 0 || range.end % sectorsize != 0 {
+            return Err(MdError::misaligned(
+                OP,
+                self.unit,
+                format!(
+                    "range {range:?} is not aligned to the sectorsize \
+                     {sectorsize}"
+                ),
+            ));
+        }
+        if range.start > range.end || range.end > mediasize {
+            return Err(MdError::out_of_range(
+                OP,
+                self.unit,
+                format!("range {range:?} exceeds the mediasize {mediasize}"),
+            ));
+        }
+        if range.is_empty() {
+            return Ok(());
+        }
+        let arg = [
+            range.start as libc::off_t,
+            (range.end - range.start) as libc::off_t,
+        ];
+        unsafe { ioctl::diocgdelete(self.file.as_raw_fd(), &arg) }
+            .map_err(|e| self.ioctl_err("DIOCGDELETE", e))?;
+        Ok(())
+    }
+
+    /// Check that buffers of the given lengths may be transferred starting at `lba`.  Returns the
+    /// starting offset in bytes.
+    fn check_io(
+        &self,
+        op: Operation,
+        lba: u64,
+        lens: impl Iterator<Item = usize>,
+    ) -> Result<u64, MdError> {
+        let sectorsize = u64::from(self.sectorsize);
+        let mut total = 0u64;
+        for len in lens {
+            let len = len as u64;
+            if len % sectorsize != 0 {
+                return Err(MdError::misaligned(
+                    op,
+                    self.unit,
+                    format!(
+                        "buffer length {len} is not a multiple of the \
+                         sectorsize {sectorsize}"
+                    ),
+                ));
+            }
+            total += len;
+        }
+        let range = lba
+            .checked_mul(sectorsize)
+            .and_then(|start| Some(start..start.checked_add(total)?));
+        let mut mediasize = self.mediasize.load(Ordering::Relaxed);
+        // Only ask the kernel if the device might have grown.
+        if range.as_ref().is_none_or(|r| r.end > mediasize) {
+            mediasize = self.mediasize()?;
+        }
+        match range {
+            Some(range) if range.end <= mediasize => Ok(range.start),
+            _ => Err(MdError::out_of_range(
+                op,
+                self.unit,
+                format!(
+                    "{} sectors at LBA {lba} exceed the mediasize {mediasize}",
+                    total / sectorsize
+                ),
+            )),
+        }
+    }
+
+    fn attr(
+        &self,
+        name: &str,
+        len: usize,
+    ) -> Result<ffi::diocgattr_arg, MdError> {
+        let mut arg: ffi::diocgattr_arg = unsafe { mem::zeroed() };
+        if name.len() >= arg.name.len() {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("attribute name {name:?} is too long"),
+            );
+            return Err(MdError::new(
+                Operation::DiskIoctl("DIOCGATTR"),
+                Some(self.unit),
+                None,
+                e,
+            ));
+        }
+        for (dst, src) in arg.name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        arg.len = len as libc::c_int;
+        unsafe { ioctl::diocgattr(self.file.as_raw_fd(), &mut arg) }
+            .map_err(|e| self.ioctl_err("DIOCGATTR", e))?;
+        Ok(arg)
+    }
+
+    /// The underlying file.
+    pub fn file(&self) -> &fs::File {
+        &self.file
+    }
+
+    /// Synthetic number of heads per cylinder, or 0 if unset.
+    pub fn fwheads(&self) -> Result<u32, MdError> {
+        let mut heads = 0;
+        unsafe { ioctl::diocgfwheads(self.file.as_raw_fd(), &mut heads) }
+            .map_err(|e| self.ioctl_err("DIOCGFWHEADS", e))?;
+        Ok(heads)
+    }
+
+    /// Synthetic number of sectors per track, or 0 if unset.
+    pub fn fwsectors(&self) -> Result<u32, MdError> {
+        let mut sectors = 0;
+        unsafe { ioctl::diocgfwsectors(self.file.as_raw_fd(), &mut sectors) }
+            .map_err(|e| self.ioctl_err("DIOCGFWSECTORS", e))?;
+        Ok(sectors)
+    }
+
+    /// The disk's identification string, as reported by `DIOCGIDENT`.
+    pub fn ident(&self) -> Result<String, MdError> {
+        let mut ident = [0u8; DISK_IDENT_SIZE];
+        unsafe { ioctl::diocgident(self.file.as_raw_fd(), &mut ident) }
+            .map_err(|e| self.ioctl_err("DIOCGIDENT", e))?;
+        Ok(String::from_utf8_lossy(cstr_bytes(&ident)).into_owned())
+    }
+
+    /// Size of the device in bytes, as currently reported by `DIOCGMEDIASIZE`.
+    pub fn mediasize(&self) -> Result<u64, MdError> {
+        let mut mediasize = 0;
+        unsafe { ioctl::diocgmediasize(self.file.as_raw_fd(), &mut mediasize) }
+            .map_err(|e| self.ioctl_err("DIOCGMEDIASIZE", e))?;
+        self.mediasize.store(mediasize as u64, Ordering::Relaxed);
+        Ok(mediasize as u64)
+    }
+
+    /// Read whole sectors, starting at logical block address `lba`, to fill `buf`.
+    ///
+    /// Unlike reading through [`Read`], this checks up front that the length of `buf` is a multiple
+    /// of the sectorsize and that the range lies within the device, failing with
+    /// [`MdError::Misaligned`] or [`MdError::OutOfRange`] rather than `EINVAL`.
+    pub fn read_sectors(
+        &self,
+        lba: u64,
+        buf: &mut [u8],
+    ) -> Result<(), MdError> {
+        let offset =
+            self.check_io(Operation::ReadDisk, lba, [buf.len()].into_iter())?;
+        self.file
+            .read_exact_at(buf, offset)
+            .map_err(|e| self.io_err(Operation::ReadDisk, e))
+    }
+
+    /// Read whole sectors, starting at logical block address `lba`, into several buffers with
+    /// `preadv`.
+    ///
+    /// The length of each buffer must be a multiple of the sectorsize.  `bufs` is consumed as the
+    /// data is read, with [`IoSliceMut::advance_slices`].
+    pub fn read_sectors_vectored(
+        &self,
+        lba: u64,
+        mut bufs: &mut [IoSliceMut<'_>],
+    ) -> Result<(), MdError> {
+        let mut offset = self.check_io(
+            Operation::ReadDisk,
+            lba,
+            bufs.iter().map(|b| b.len()),
+        )?;
+        IoSliceMut::advance_slices(&mut bufs, 0);
+        while !bufs.is_empty() {
+            let r = unsafe {
+                libc::preadv(
+                    self.file.as_raw_fd(),
+                    bufs.as_ptr().cast(),
+                    bufs.len().min(IOV_MAX) as libc::c_int,
+                    offset as libc::off_t,
+                )
+            };
+            let n = match r {
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(self.io_err(Operation::ReadDisk, e));
+                }
+                0 => {
+                    let e = io::ErrorKind::UnexpectedEof.into();
+                    return Err(self.io_err(Operation::ReadDisk, e));
+                }
+                n => n as usize,
+            };
+            IoSliceMut::advance_slices(&mut bufs, n);
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Sectorsize of the device in bytes.
+    pub fn sectorsize(&self) -> Result<u32, MdError> {
+        let mut sectorsize = 0;
+        unsafe {
+            ioctl::diocgsectorsize(self.file.as_raw_fd(), &mut sectorsize)
+        }
+        .map_err(|e| self.ioctl_err("DIOCGSECTORSIZE", e))?;
+        Ok(sectorsize)
+    }
+
+    /// Offset of the first stripe, in bytes.
+    pub fn stripeoffset(&self) -> Result<u64, MdError> {
+        let mut offset = 0;
+        unsafe { ioctl::diocgstripeoffset(self.file.as_raw_fd(), &mut offset) }
+            .map_err(|e| self.ioctl_err("DIOCGSTRIPEOFFSET", e))?;
+        Ok(offset as u64)
+    }
+
+    /// Stripe size in bytes, or 0 if the device isn't striped.
+    pub fn stripesize(&self) -> Result<u64, MdError> {
+        let mut size = 0;
+        unsafe { ioctl::diocgstripesize(self.file.as_raw_fd(), &mut size) }
+            .map_err(|e| self.ioctl_err("DIOCGSTRIPESIZE", e))?;
+        Ok(size as u64)
+    }
+
+    /// Report the device's unit number. e.g. the "0" in "md0".
+    pub fn unit(&self) -> u32 {
+        self.unit
+    }
+
+    /// Write whole sectors from `buf`, starting at logical block address `lba`.
+    ///
+    /// Unlike writing through [`Write`], this checks up front that the length of `buf` is a
+    /// multiple of the sectorsize and that the range lies within the device, failing with
+    /// [`MdError::Misaligned`] or [`MdError::OutOfRange`] rather than `EINVAL`.
+    pub fn write_sectors(&self, lba: u64, buf: &[u8]) -> Result<(), MdError> {
+        let offset =
+            self.check_io(Operation::WriteDisk, lba, [buf.len()].into_iter())?;
+        self.file
+            .write_all_at(buf, offset)
+            .map_err(|e| self.io_err(Operation::WriteDisk, e))
+    }
+
+    /// Write whole sectors from several buffers with `pwritev`, starting at logical block address
+    /// `lba`.
+    ///
+    /// The length of each buffer must be a multiple of the sectorsize.  `bufs` is consumed as the
+    /// data is written, with [`IoSlice::advance_slices`].
+    pub fn write_sectors_vectored(
+        &self,
+        lba: u64,
+        mut bufs: &mut [IoSlice<'_>],
+    ) -> Result<(), MdError> {
+        let mut offset = self.check_io(
+            Operation::WriteDisk,
+            lba,
+            bufs.iter().map(|b| b.len()),
+        )?;
+        IoSlice::advance_slices(&mut bufs, 0);
+        while !bufs.is_empty() {
+            let r = unsafe {
+                libc::pwritev(
+                    self.file.as_raw_fd(),
+                    bufs.as_ptr().cast(),
+                    bufs.len().min(IOV_MAX) as libc::c_int,
+                    offset as libc::off_t,
+                )
+            };
+            let n = match r {
+                -1 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(self.io_err(Operation::WriteDisk, e));
+                }
+                0 => {
+                    let e = io::ErrorKind::WriteZero.into();
+                    return Err(self.io_err(Operation::WriteDisk, e));
+                }
+                n => n as usize,
+            };
+            IoSlice::advance_slices(&mut bufs, n);
+            offset += n as u64;
+        }
+        Ok(())
+    }
+}
+
+impl AsFd for MdDisk {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl FileExt for MdDisk {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.file.write_at(buf, offset)
+    }
+}
+
+impl From<MdDisk> for fs::File {
+    fn from(disk: MdDisk) -> Self {
+        disk.file
+    }
+}
+
+impl Read for MdDisk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Seek for MdDisk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Write for MdDisk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
@@ -0,0 +1,463 @@
+//! Error types
+use std::{
+    error::Error,
+    fmt,
+    io,
+    path::{Path, PathBuf},
+};
+
+use super::{MdOptions, Size};
+
+/// The operation that an [`MdError`] originated from.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Operation {
+    /// Opening the md control device, `/dev/mdctl`.
+    Open,
+    /// Creating a device, with `MDIOCATTACH`.
+    Attach,
+    /// Destroying a device, with `MDIOCDETACH`.
+    Detach,
+    /// Querying a device's configuration, with `MDIOCQUERY`.
+    Query,
+    /// Listing all devices, with `MDIOCLIST`.
+    List,
+    /// Changing a device's size, with `MDIOCRESIZE`.
+    Resize,
+    /// Changing the size of a vnode-backed device's backing file.
+    ResizeFile,
+    /// Verifying a device against an [`MdToken`](crate::MdToken).
+    Reclaim,
+    /// Checking a [`Builder`](crate::Builder)'s configuration before creating a device.
+    Validate,
+    /// Waiting for a device to become ready, with [`Md::wait_ready`](crate::Md::wait_ready).
+    Wait,
+    /// Opening the device itself, like `/dev/md0`, with [`Md::open`](crate::Md::open).
+    OpenDisk,
+    /// A disk ioctl on an open device, like `DIOCGMEDIASIZE`.
+    DiskIoctl(&'static str),
+    /// Inspecting a vnode-backed device's backing file.
+    StatFile,
+    /// Reading from an open device.
+    ReadDisk,
+    /// Writing to an open device.
+    WriteDisk,
+    /// Writing a device's contents to a file.
+    Export,
+    /// Taking ownership of an existing device, with [`Md::from_unit`](crate::Md::from_unit).
+    Own,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Operation::Open => "open /dev/mdctl",
+            Operation::Attach => "MDIOCATTACH",
+            Operation::Detach => "MDIOCDETACH",
+            Operation::Query => "MDIOCQUERY",
+            Operation::List => "MDIOCLIST",
+            Operation::Resize => "MDIOCRESIZE",
+            Operation::ResizeFile => "resize backing file",
+            Operation::Reclaim => "reclaim",
+            Operation::Validate => "validate",
+            Operation::Wait => "wait for device",
+            Operation::OpenDisk => "open device",
+            Operation::DiskIoctl(name) => name,
+            Operation::StatFile => "stat backing file",
+            Operation::ReadDisk => "read device",
+            Operation::WriteDisk => "write device",
+            Operation::Export => "export device",
+            Operation::Own => "take ownership",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A problem with a [`Builder`](crate::Builder)'s configuration.
+///
+/// Reported by [`MdError::InvalidConfig`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ConfigProblem {
+    /// The sectorsize is not a power of two.
+    SectorsizeNotPowerOfTwo(u32),
+    /// The sectorsize is larger than the kernel's maximum I/O size, `kern.maxphys`.
+    SectorsizeTooLarge {
+        /// Requested sectorsize
+        sectorsize: u32,
+        /// The maximum I/O size
+        maxphys:    u64,
+    },
+    /// The size is not a multiple of the sectorsize.
+    SizeNotMultipleOfSectorsize {
+        /// Requested size in bytes
+        size:       u64,
+        /// Requested sectorsize, or the default
+        sectorsize: u32,
+    },
+    /// The size of a swap-backed device is not a multiple of the page size.
+    SizeNotMultipleOfPageSize {
+        /// Requested size in bytes
+        size:      u64,
+        /// The system's page size
+        page_size: u64,
+    },
+    /// The size is zero or negative, for a device type that requires an explicit size.
+    SizeNotPositive(i64),
+    /// The string given to [`Builder::size_str`](crate::Builder::size_str) could not be parsed.
+    InvalidSize(String),
+    /// The size is too large to represent.
+    SizeOverflow(Size),
+    /// Only one of `heads_per_cylinder` and `sectors_per_track` was set.  The kernel would
+    /// silently ignore it.
+    IncompleteGeometry,
+    /// The synthetic geometry is negative.
+    NegativeGeometry,
+    /// Options that only apply to vnode-backed devices were requested for another type.
+    VnodeOnlyOptions(MdOptions),
+    /// The label is too long.
+    LabelTooLong,
+    /// The backing file's path is too long.
+    PathTooLong,
+    /// The range given to [`Builder::unit_range`](crate::Builder::unit_range) is empty.
+    EmptyUnitRange,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigProblem::SectorsizeNotPowerOfTwo(sectorsize) => {
+                write!(f, "sectorsize {sectorsize} is not a power of two")
+            }
+            ConfigProblem::SizeNotMultipleOfSectorsize { size, sectorsize } => {
+                write!(
+                    f,
+                    "size {size} is not a multiple of the sectorsize \
+                     {sectorsize}"
+                )
+            }
+            ConfigProblem::SectorsizeTooLarge {
+                sectorsize,
+                maxphys,
+            } => {
+                write!(
+                    f,
+                    "sectorsize {sectorsize} exceeds the maximum I/O size \
+                     {maxphys}"
+                )
+            }
+            ConfigProblem::SizeNotMultipleOfPageSize { size, page_size } => {
+                write!(
+                    f,
+                    "size {size} is not a multiple of the page size \
+                     {page_size}"
+                )
+            }
+            ConfigProblem::SizeNotPositive(size) => {
+                write!(f, "size {size} is not positive")
+            }
+            ConfigProblem::InvalidSize(size) => {
+                write!(f, "invalid size {size:?}")
+            }
+            ConfigProblem::SizeOverflow(size) => {
+                write!(f, "size {size} is too large")
+            }
+            ConfigProblem::IncompleteGeometry => {
+                write!(
+                    f,
+                    "heads_per_cylinder and sectors_per_track must be set \
+                     together"
+                )
+            }
+            ConfigProblem::NegativeGeometry => {
+                write!(f, "synthetic geometry must not be negative")
+            }
+            ConfigProblem::VnodeOnlyOptions(options) => {
+                write!(f, "options {options} require a vnode-backed device")
+            }
+            ConfigProblem::LabelTooLong => write!(f, "label is too long"),
+            ConfigProblem::PathTooLong => {
+                write!(f, "backing file's path is too long")
+            }
+            ConfigProblem::EmptyUnitRange => write!(f, "unit range is empty"),
+        }
+    }
+}
+
+/// Details common to every [`MdError`].
+#[derive(Debug)]
+pub struct ErrorContext {
+    op:     Operation,
+    unit:   Option<u32>,
+    path:   Option<PathBuf>,
+    source: io::Error,
+}
+
+impl ErrorContext {
+    /// The operation that failed.
+    pub fn operation(&self) -> Operation {
+        self.op
+    }
+
+    /// The path involved, such as a vnode-backed device's backing file, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The underlying error.
+    pub fn source(&self) -> &io::Error {
+        &self.source
+    }
+
+    /// The unit number of the device involved, if known.
+    pub fn unit(&self) -> Option<u32> {
+        self.unit
+    }
+}
+
+/// An error from this crate, classified by likely cause.
+///
+/// Every variant carries an [`ErrorContext`] describing the failed operation.  For compatibility,
+/// `MdError` can be converted into an [`io::Error`].
+///
+/// # Example
+/// ```no_run
+/// # use mdconfig::{Builder, MdError};
+/// match Builder::null(1 << 20).unit(7).create() {
+///     Ok(md) => println!("Created {}", md.name()),
+///     Err(MdError::UnitInUse(_)) => println!("md7 already exists"),
+///     Err(e) => panic!("{e}"),
+/// }
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MdError {
+    /// `/dev/mdctl` does not exist, usually because `geom_md` is not loaded.
+    NotLoaded(ErrorContext),
+    /// The caller lacks the privilege required, usually because it isn't root.
+    PermissionDenied(ErrorContext),
+    /// The explicitly requested unit number is already in use.
+    UnitInUse(ErrorContext),
+    /// No device with the given unit number exists.
+    NoSuchUnit(ErrorContext),
+    /// The device is open by some other consumer.
+    Busy(ErrorContext),
+    /// Shrinking the device requires the `force` flag.
+    ResizeNeedsForce(ErrorContext),
+    /// The operation did not complete within the allotted time.
+    TimedOut(ErrorContext),
+    /// An offset, length, or buffer is not aligned to the device's sectorsize.
+    Misaligned(ErrorContext),
+    /// The requested range extends beyond the end of the device.
+    OutOfRange(ErrorContext),
+    /// The device's type doesn't permit the operation.  For example, a preloaded device can't be
+    /// owned.
+    WrongType(ErrorContext),
+    /// The [`Builder`](crate::Builder)'s configuration is invalid.  Every problem found is listed.
+    InvalidConfig(ErrorContext, Vec<ConfigProblem>),
+    /// Any other error.
+    Other(ErrorContext),
+}
+
+impl MdError {
+    /// Classify an error returned by the operating system.
+    pub(crate) fn new(
+        op: Operation,
+        unit: Option<u32>,
+        path: Option<&Path>,
+        source: io::Error,
+    ) -> Self {
+        let errno = source.raw_os_error();
+        let ctx = ErrorContext {
+            op,
+            unit,
+            path: path.map(Path::to_owned),
+            source,
+        };
+        match (op, errno) {
+            (_, Some(libc::EPERM | libc::EACCES)) => {
+                MdError::PermissionDenied(ctx)
+            }
+            (Operation::Open, Some(libc::ENOENT)) => MdError::NotLoaded(ctx),
+            // Without a requested unit, EBUSY can't mean that the unit is
+            // taken; the kernel picks a free one.
+            (Operation::Attach, Some(libc::EBUSY)) if unit.is_none() => {
+                MdError::Busy(ctx)
+            }
+            (Operation::Attach, Some(libc::EEXIST | libc::EBUSY)) => {
+                MdError::UnitInUse(ctx)
+            }
+            (
+                Operation::Detach
+                | Operation::Query
+                | Operation::Resize
+                | Operation::OpenDisk,
+                Some(libc::ENOENT | libc::ENXIO),
+            ) => MdError::NoSuchUnit(ctx),
+            (
+                Operation::DiskIoctl(_)
+                | Operation::ReadDisk
+                | Operation::WriteDisk,
+                Some(libc::ENXIO),
+            ) => MdError::NoSuchUnit(ctx),
+            (Operation::Resize, Some(libc::EBUSY)) => {
+                MdError::ResizeNeedsForce(ctx)
+            }
+            (_, Some(libc::EBUSY)) => MdError::Busy(ctx),
+            (_, Some(libc::ETIMEDOUT)) => MdError::TimedOut(ctx),
+            _ => MdError::Other(ctx),
+        }
+    }
+
+    /// Report problems found while validating a [`Builder`](crate::Builder).
+    pub(crate) fn invalid_config(
+        unit: Option<u32>,
+        path: Option<&Path>,
+        problems: Vec<ConfigProblem>,
+    ) -> Self {
+        let msg = problems
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ");
+        let ctx = ErrorContext {
+            op: Operation::Validate,
+            unit,
+            path: path.map(Path::to_owned),
+            source: io::Error::new(io::ErrorKind::InvalidInput, msg),
+        };
+        MdError::InvalidConfig(ctx, problems)
+    }
+
+    /// Report an offset, length, or buffer that is not aligned to the sectorsize.
+    pub(crate) fn misaligned(op: Operation, unit: u32, msg: String) -> Self {
+        MdError::Misaligned(ErrorContext {
+            op,
+            unit: Some(unit),
+            path: None,
+            source: io::Error::new(io::ErrorKind::InvalidInput, msg),
+        })
+    }
+
+    /// Report a range that extends beyond the end of the device.
+    pub(crate) fn out_of_range(op: Operation, unit: u32, msg: String) -> Self {
+        MdError::OutOfRange(ErrorContext {
+            op,
+            unit: Some(unit),
+            path: None,
+            source: io::Error::new(io::ErrorKind::InvalidInput, msg),
+        })
+    }
+
+    /// Report a device whose type doesn't permit the operation.
+    pub(crate) fn wrong_type(op: Operation, unit: u32, msg: String) -> Self {
+        MdError::WrongType(ErrorContext {
+            op,
+            unit: Some(unit),
+            path: None,
+            source: io::Error::new(io::ErrorKind::InvalidInput, msg),
+        })
+    }
+
+    /// Details about the failed operation.
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            MdError::NotLoaded(ctx)
+            | MdError::PermissionDenied(ctx)
+            | MdError::UnitInUse(ctx)
+            | MdError::NoSuchUnit(ctx)
+            | MdError::Busy(ctx)
+            | MdError::ResizeNeedsForce(ctx)
+            | MdError::TimedOut(ctx)
+            | MdError::Misaligned(ctx)
+            | MdError::OutOfRange(ctx)
+            | MdError::WrongType(ctx)
+            | MdError::InvalidConfig(ctx, _)
+            | MdError::Other(ctx) => ctx,
+        }
+    }
+
+    fn into_context(self) -> ErrorContext {
+        match self {
+            MdError::NotLoaded(ctx)
+            | MdError::PermissionDenied(ctx)
+            | MdError::UnitInUse(ctx)
+            | MdError::NoSuchUnit(ctx)
+            | MdError::Busy(ctx)
+            | MdError::ResizeNeedsForce(ctx)
+            | MdError::TimedOut(ctx)
+            | MdError::Misaligned(ctx)
+            | MdError::OutOfRange(ctx)
+            | MdError::WrongType(ctx)
+            | MdError::InvalidConfig(ctx, _)
+            | MdError::Other(ctx) => ctx,
+        }
+    }
+
+    /// The operation that failed.
+    pub fn operation(&self) -> Operation {
+        self.context().op
+    }
+
+    /// The path involved, such as a vnode-backed device's backing file, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.context().path()
+    }
+
+    /// The OS error number, if any.  See [`io::Error::raw_os_error`].
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.context().source.raw_os_error()
+    }
+
+    /// The unit number of the device involved, if known.
+    pub fn unit(&self) -> Option<u32> {
+        self.context().unit
+    }
+}
+
+impl fmt::Display for MdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ctx = self.context();
+        write!(f, "{}", ctx.op)?;
+        if let Some(unit) = ctx.unit {
+            write!(f, " md{unit}")?;
+        }
+        if let Some(path) = &ctx.path {
+            write!(f, " ({})", path.display())?;
+        }
+        write!(f, ": {}", ctx.source)
+    }
+}
+
+impl Error for MdError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.context().source)
+    }
+}
+
+/// Errors that originated from the operating system convert to the original `io::Error`, so that
+/// [`io::Error::raw_os_error`] keeps working.  Other errors are wrapped.
+impl From<MdError> for io::Error {
+    fn from(e: MdError) -> io::Error {
+        if e.raw_os_error().is_some() {
+            e.into_context().source
+        } else {
+            io::Error::new(e.context().source.kind(), e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// EBUSY from an autounit attach can't mean that the unit is taken.
+    #[test]
+    fn attach_ebusy() {
+        let ebusy = || io::Error::from_raw_os_error(libc::EBUSY);
+        let e = MdError::new(Operation::Attach, None, None, ebusy());
+        assert!(matches!(e, MdError::Busy(_)));
+        let e = MdError::new(Operation::Attach, Some(7), None, ebusy());
+        assert!(matches!(e, MdError::UnitInUse(_)));
+    }
+}
@@ -0,0 +1,201 @@
+//! Copying a device's contents to a file
+use std::{fs, io, os::unix::fs::FileExt, path::Path};
+
+use super::{AlignedBuf, MdDisk, MdError, Operation};
+
+/// Controls how [`Md::export_with`](crate::Md::export_with) copies a device.
+///
+/// By default, it copies 1 MiB at a time, and the output file is as long as the device.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ExportOptions {
+    chunk_size: usize,
+    truncate:   bool,
+}
+
+impl ExportOptions {
+    /// Read this many bytes at a time.
+    ///
+    /// It will be rounded up to a multiple of 4096 bytes, or of the sectorsize if that's larger,
+    /// and limited to the size of the device.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Omit any zeros at the end of the device from the output file, making it shorter.
+    pub fn truncate_trailing_zeros(mut self, truncate: bool) -> Self {
+        self.truncate = truncate;
+        self
+    }
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        ExportOptions {
+            chunk_size: 1 << 20,
+            truncate:   false,
+        }
+    }
+}
+
+/// Summarizes a completed [`Md::export_with`](crate::Md::export_with).
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct ExportStats {
+    /// Bytes read from the device.
+    pub bytes_read:    u64,
+    /// Bytes written to the output file.
+    pub bytes_written: u64,
+    /// Bytes of zeros that were left as holes in the output file instead of being written.
+    pub bytes_skipped: u64,
+    /// Final length of the output file.
+    pub file_len:      u64,
+}
+
+/// Zeros are detected at this granularity, or the sectorsize if larger.  Smaller holes wouldn't
+/// save any space on most file systems.
+const HOLE_SIZE: usize = 4096;
+
+pub(crate) fn export<F>(
+    disk: &MdDisk,
+    path: &Path,
+    opts: &ExportOptions,
+    mut progress: F,
+) -> Result<ExportStats, MdError>
+where
+    F: FnMut(u64, u64),
+{
+    let sectorsize = disk.sectorsize()? as usize;
+    let block = HOLE_SIZE.max(sectorsize);
+    let total = disk.mediasize()?;
+    // Don't allocate more than the whole device, even if asked to.
+    let chunk = opts
+        .chunk_size
+        .clamp(1, usize::try_from(total).unwrap_or(usize::MAX).max(1))
+        .div_ceil(block)
+        .checked_mul(block)
+        .unwrap_or(usize::MAX / block * block);
+    let file_err =
+        |e| MdError::new(Operation::Export, Some(disk.unit()), Some(path), e);
+    let f = fs::File::create(path).map_err(file_err)?;
+
+    let mut buf = AlignedBuf::new(chunk, sectorsize);
+    let mut stats = ExportStats::default();
+    // End of the last nonzero data written.
+    let mut data_end = 0;
+    while stats.bytes_read < total {
+        let offset = stats.bytes_read;
+        let len = (total - offset).min(chunk as u64) as usize;
+        let data = &mut buf[..len];
+        disk.read_sectors(offset / sectorsize as u64, data)?;
+        let (written, end) =
+            write_runs(&f, data, offset, block).map_err(file_err)?;
+        stats.bytes_written += written;
+        data_end = end.unwrap_or(data_end);
+        stats.bytes_read += len as u64;
+        progress(stats.bytes_read, total);
+    }
+    stats.bytes_skipped = stats.bytes_read - stats.bytes_written;
+    stats.file_len = if opts.truncate { data_end } else { total };
+    // Extend the file over any trailing hole.
+    f.set_len(stats.file_len).map_err(file_err)?;
+    Ok(stats)
+}
+
+/// Write each run of nonzero `block`-sized pieces of `data` to `f` at `offset`, and skip the rest.
+///
+/// Returns the number of bytes written and the end of the last run, if any.
+fn write_runs(
+    f: &fs::File,
+    data: &[u8],
+    offset: u64,
+    block: usize,
+) -> io::Result<(u64, Option<u64>)> {
+    let mut written = 0;
+    let mut end = None;
+    let mut run: Option<usize> = None;
+    // A final None ends any run at the end of the data.
+    let blocks = data.chunks(block).map(Some).chain([None]);
+    for (i, b) in blocks.enumerate() {
+        let start = (i * block).min(data.len());
+        match (b.is_none_or(is_zero), run) {
+            (false, None) => run = Some(start),
+            (true, Some(r)) => {
+                f.write_all_at(&data[r..start], offset + r as u64)?;
+                written += (start - r) as u64;
+                end = Some(offset + start as u64);
+                run = None;
+            }
+            _ => (),
+        }
+    }
+    Ok((written, end))
+}
+
+fn is_zero(buf: &[u8]) -> bool {
+    // Comparing words is much faster than comparing bytes.
+    let (head, words, tail) = unsafe { buf.align_to::<u64>() };
+    head.iter().all(|b| *b == 0)
+        && words.iter().all(|w| *w == 0)
+        && tail.iter().all(|b| *b == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_zero() {
+        // Bytes 1..8 are an unaligned head, and 64..67 are a tail.
+        let mut buf = AlignedBuf::new(72, 8);
+        assert!(super::is_zero(&buf[1..67]));
+        assert!(super::is_zero(&buf[..0]));
+        for i in [1, 7, 8, 33, 63, 64, 66] {
+            buf[i] = 1;
+            assert!(!super::is_zero(&buf[1..67]), "{i}");
+            buf[i] = 0;
+        }
+        buf[67] = 1;
+        assert!(super::is_zero(&buf[1..67]));
+    }
+
+    /// Only the nonzero blocks are written, at the right offsets
+    #[test]
+    fn write_runs() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let mut data = vec![0u8; 5 * 4096 + 512];
+        data[4096..3 * 4096].fill(0xa5);
+        data[5 * 4096 + 511] = 0x5a;
+        let offset = 1 << 20;
+        let (written, end) =
+            super::write_runs(tf.as_file(), &data, offset, 4096).unwrap();
+        assert_eq!(written, 2 * 4096 + 512);
+        assert_eq!(end, Some(offset + data.len() as u64));
+        let mut buf = vec![0u8; data.len()];
+        tf.as_file().read_exact_at(&mut buf, offset).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    /// A run that ends before the end of the data ends there
+    #[test]
+    fn write_runs_trailing_zeros() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let mut data = vec![0u8; 4 * 4096];
+        data[4096] = 1;
+        let (written, end) =
+            super::write_runs(tf.as_file(), &data, 0, 4096).unwrap();
+        assert_eq!(written, 4096);
+        assert_eq!(end, Some(2 * 4096));
+        assert_eq!(tf.as_file().metadata().unwrap().len(), 2 * 4096);
+    }
+
+    #[test]
+    fn write_runs_zeros() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let data = vec![0u8; 4 * 4096];
+        let (written, end) =
+            super::write_runs(tf.as_file(), &data, 0, 4096).unwrap();
+        assert_eq!(written, 0);
+        assert_eq!(end, None);
+        assert_eq!(tf.as_file().metadata().unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,195 @@
+//! Inspecting GEOM state
+use std::io;
+
+/// The access counts of a provider with no consumers.
+pub(crate) const IDLE: &str = "r0w0e0";
+
+/// Report the access mode of the GEOM provider with the given name, like "r1w0e0", or `None` if
+/// there is no such provider.
+pub(crate) fn provider_mode(name: &str) -> io::Result<Option<String>> {
+    let xml = confxml()?;
+    Ok(parse_mode(&xml, name).map(str::to_owned))
+}
+
+/// Find a provider's mode within the output of `kern.geom.confxml`.
+fn parse_mode<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    // Consumers also contain a `<provider ref="..."/>` element, so only match definitions.
+    xml.split("<provider id=")
+        .skip(1)
+        .filter_map(|p| p.split("</provider>").next())
+        .find(|p| element(p, "name") == Some(name))
+        .and_then(|p| element(p, "mode"))
+}
+
+/// The contents of the first `<tag>` element in `xml`.
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{tag}>");
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(&xml[start..end])
+}
+
+#[cfg(target_os = "freebsd")]
+fn confxml() -> io::Result<String> {
+    let name = c"kern.geom.confxml";
+    let mut len = 0;
+    let r = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            std::ptr::null_mut(),
+            &mut len,
+            std::ptr::null(),
+            0,
+        )
+    };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The configuration may grow between the two calls.
+    len += len / 8;
+    let mut buf = vec![0u8; len];
+    let r = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            &mut len,
+            std::ptr::null(),
+            0,
+        )
+    };
+    if r != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(len);
+    Ok(String::from_utf8_lossy(super::cstr_bytes(&buf)).into_owned())
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn confxml() -> io::Result<String> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Abridged output of `kern.geom.confxml`, in the layout of `geom_dump.c`.  The DEV geoms come
+    /// before the MD class, and md0's DEV consumer has it open.
+    const CONFXML: &str = r#"<mesh>
+  <class id="0xffffffff81a1c8a0">
+    <name>DEV</name>
+    <geom id="0xfffff80003a4e100">
+      <class ref="0xffffffff81a1c8a0"/>
+      <name>ada0</name>
+      <rank>2</rank>
+      <consumer id="0xfffff80003a4e180">
+        <geom ref="0xfffff80003a4e100"/>
+        <provider ref="0xfffff80003a4d000"/>
+        <mode>r1w1e1</mode>
+      </consumer>
+    </geom>
+    <geom id="0xfffff80012c0a200">
+      <class ref="0xffffffff81a1c8a0"/>
+      <name>md0</name>
+      <rank>2</rank>
+      <consumer id="0xfffff80012c0a280">
+        <geom ref="0xfffff80012c0a200"/>
+        <provider ref="0xfffff80012c0b000"/>
+        <mode>r1w1e0</mode>
+      </consumer>
+    </geom>
+  </class>
+  <class id="0xffffffff81a2b0c0">
+    <name>DISK</name>
+    <geom id="0xfffff80003a4c000">
+      <class ref="0xffffffff81a2b0c0"/>
+      <name>ada0</name>
+      <rank>1</rank>
+      <config>
+        <fwheads>16</fwheads>
+        <fwsectors>63</fwsectors>
+      </config>
+      <provider id="0xfffff80003a4d000">
+        <geom ref="0xfffff80003a4c000"/>
+        <mode>r1w1e1</mode>
+        <name>ada0</name>
+        <mediasize>128849018880</mediasize>
+        <sectorsize>512</sectorsize>
+        <stripesize>4096</stripesize>
+        <stripeoffset>0</stripeoffset>
+        <config>
+          <fwheads>16</fwheads>
+          <fwsectors>63</fwsectors>
+          <rotationrate>0</rotationrate>
+          <ident>S3Z9NB0K123456</ident>
+          <lunid>5002538e40a1b2c3</lunid>
+          <descr>Samsung SSD 860 EVO 250GB</descr>
+        </config>
+      </provider>
+    </geom>
+  </class>
+  <class id="0xffffffff81a3e1f0">
+    <name>MD</name>
+    <geom id="0xfffff80012c09100">
+      <class ref="0xffffffff81a3e1f0"/>
+      <name>md0</name>
+      <rank>1</rank>
+      <provider id="0xfffff80012c0b000">
+        <geom ref="0xfffff80012c09100"/>
+        <mode>r0w0e0</mode>
+        <name>md0</name>
+        <mediasize>1048576</mediasize>
+        <sectorsize>512</sectorsize>
+        <stripesize>0</stripesize>
+        <stripeoffset>0</stripeoffset>
+        <config>
+          <unit>0</unit>
+          <sectorsize>512</sectorsize>
+          <length>1048576</length>
+          <compression>off</compression>
+          <access>read-write</access>
+          <type>malloc</type>
+        </config>
+      </provider>
+    </geom>
+    <geom id="0xfffff80012c0c100">
+      <class ref="0xffffffff81a3e1f0"/>
+      <name>md10</name>
+      <rank>1</rank>
+      <provider id="0xfffff80012c0d000">
+        <geom ref="0xfffff80012c0c100"/>
+        <mode>r1w0e0</mode>
+        <name>md10</name>
+        <mediasize>1048576</mediasize>
+        <sectorsize>512</sectorsize>
+        <stripesize>0</stripesize>
+        <stripeoffset>0</stripeoffset>
+      </provider>
+    </geom>
+  </class>
+</mesh>
+"#;
+
+    /// A consumer's mode must not be mistaken for the provider's
+    #[test]
+    fn consumer_before_provider() {
+        assert_eq!(parse_mode(CONFXML, "md0"), Some(IDLE));
+    }
+
+    #[test]
+    fn disk() {
+        assert_eq!(parse_mode(CONFXML, "ada0"), Some("r1w1e1"));
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(parse_mode("<mesh>\n</mesh>\n", "md0"), None);
+    }
+
+    /// "md1" is a prefix of "md10", but not the same provider
+    #[test]
+    fn missing() {
+        assert_eq!(parse_mode(CONFXML, "md1"), None);
+        assert_eq!(parse_mode(CONFXML, "md10"), Some("r1w0e0"));
+    }
+}
@@ -1,4 +1,6 @@
 #![warn(missing_docs)]
+// Methods like `try_destroy` must return ownership of `self` on error.
+#![allow(clippy::result_large_err)]
 //! Rust bindings to FreeBSD's
 //! [md(4)](https://man.freebsd.org/cgi/man.cgi?query=md) driver.
 //!
@@ -11,18 +13,55 @@
 //! The main entry point is the [`Builder`] struct.  Use it to construct an [`Md`] device which
 //! will automatically destroy itself when dropped.
 use std::{
-    ffi::OsStr,
+    ffi::{CStr, OsStr},
+    fmt,
     fs,
     io,
-    os::{
-        fd::AsRawFd,
-        unix::{ffi::OsStrExt, fs::MetadataExt},
-    },
+    ops::{Deref, Range},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
     path::{Path, PathBuf},
     ptr,
+    str::FromStr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use nix::ioctl_readwrite;
+use bitflags::bitflags;
+use nix::{ioctl_read, ioctl_readwrite, ioctl_write_ptr};
+
+mod buf;
+mod control;
+mod disk;
+mod error;
+mod export;
+mod geom;
+mod owner;
+mod registry;
+mod set;
+mod size;
+mod token;
+mod verify;
+mod vnode;
+pub use buf::AlignedBuf;
+pub use control::MdControl;
+pub use disk::MdDisk;
+pub use error::{ConfigProblem, ErrorContext, MdError, Operation};
+pub use export::{ExportOptions, ExportStats};
+pub use owner::{OwnerTag, reap_orphans};
+pub use registry::{
+    detach_registered,
+    enable_registry,
+    install_signal_handlers,
+};
+pub use set::MdSet;
+pub use size::Size;
+pub use token::MdToken;
+pub use verify::{Mismatch, MismatchKind, Verifier, VerifyReport};
+pub use vnode::FileGrowth;
 
 cfg_if::cfg_if! {
     if #[cfg(target_pointer_width = "64")] {
@@ -40,7 +79,19 @@ mod ioctl {
 
     ioctl_readwrite!(mdiocattach, 'm', 0, ffi::md_ioctl);
     ioctl_readwrite!(mdiocdetach, 'm', 1, ffi::md_ioctl);
+    ioctl_readwrite!(mdiocquery, 'm', 2, ffi::md_ioctl);
+    ioctl_readwrite!(mdioclist, 'm', 3, ffi::md_ioctl);
     ioctl_readwrite!(mdiocresize, 'm', 4, ffi::md_ioctl);
+
+    ioctl_read!(diocgsectorsize, 'd', 128, libc::c_uint);
+    ioctl_read!(diocgmediasize, 'd', 129, libc::off_t);
+    ioctl_read!(diocgfwsectors, 'd', 130, libc::c_uint);
+    ioctl_read!(diocgfwheads, 'd', 131, libc::c_uint);
+    ioctl_write_ptr!(diocgdelete, 'd', 136, [libc::off_t; 2]);
+    ioctl_read!(diocgident, 'd', 137, [u8; disk::DISK_IDENT_SIZE]);
+    ioctl_read!(diocgstripesize, 'd', 139, libc::off_t);
+    ioctl_read!(diocgstripeoffset, 'd', 140, libc::off_t);
+    ioctl_readwrite!(diocgattr, 'd', 142, ffi::diocgattr_arg);
 }
 
 macro_rules! set_bool {
@@ -53,6 +104,289 @@ macro_rules! set_bool {
     };
 }
 
+/// Interpret a NUL-terminated buffer filled in by the kernel.
+fn cstr_bytes(buf: &[u8]) -> &[u8] {
+    CStr::from_bytes_until_nul(buf)
+        .map(CStr::to_bytes)
+        .unwrap_or(buf)
+}
+
+/// The kind of backing store used by an [`Md`] device.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum MdType {
+    /// Backed by kernel memory.  See [`Builder::malloc`].
+    Malloc,
+    /// A memory disk preloaded by
+    /// [loader(8)](https://man.freebsd.org/cgi/man.cgi?query=loader).
+    Preload,
+    /// Backed by a file.  See [`Builder::vnode`].
+    Vnode,
+    /// Backed by swap.  See [`Builder::swap`].
+    Swap,
+    /// A bitsink.  See [`Builder::null`].
+    Null,
+}
+
+impl MdType {
+    fn from_raw(md_type: ffi::md_types) -> Option<Self> {
+        match md_type {
+            ffi::md_types_MD_MALLOC => Some(MdType::Malloc),
+            ffi::md_types_MD_PRELOAD => Some(MdType::Preload),
+            ffi::md_types_MD_VNODE => Some(MdType::Vnode),
+            ffi::md_types_MD_SWAP => Some(MdType::Swap),
+            ffi::md_types_MD_NULL => Some(MdType::Null),
+            _ => None,
+        }
+    }
+}
+
+/// Spelled the same way as `mdconfig -t`.
+impl fmt::Display for MdType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            MdType::Malloc => "malloc",
+            MdType::Preload => "preload",
+            MdType::Vnode => "vnode",
+            MdType::Swap => "swap",
+            MdType::Null => "null",
+        };
+        f.write_str(s)
+    }
+}
+
+impl FromStr for MdType {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s {
+            "malloc" => Ok(MdType::Malloc),
+            "preload" => Ok(MdType::Preload),
+            "vnode" => Ok(MdType::Vnode),
+            "swap" => Ok(MdType::Swap),
+            "null" => Ok(MdType::Null),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown md type {s:?}"),
+            )),
+        }
+    }
+}
+
+bitflags! {
+    /// Options that control an md device's behavior.
+    ///
+    /// `Display` and `FromStr` use the same spelling as `mdconfig -o`, separated by commas.  Like
+    /// repeated `-o` flags, `FromStr` also accepts the `ro` abbreviation and `no` prefixes, which
+    /// clear options set by earlier words.  Bits without a name are written in hexadecimal, like
+    /// `0x100000`, and can be parsed back.
+    ///
+    /// # Example
+    /// ```
+    /// # use mdconfig::MdOptions;
+    /// let options = "compress,reserve".parse::<MdOptions>().unwrap();
+    /// assert_eq!(options, MdOptions::COMPRESS | MdOptions::RESERVE);
+    /// assert_eq!(options.to_string(), "reserve,compress");
+    /// let options = "ro,cache,nocache".parse::<MdOptions>().unwrap();
+    /// assert_eq!(options, MdOptions::READONLY);
+    /// ```
+    #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+    pub struct MdOptions: u32 {
+        /// Cluster I/O requests for vnode and swap backed devices.
+        const CLUSTER = ffi::MD_CLUSTER;
+        /// Allocate and reserve all needed storage from the start.
+        const RESERVE = ffi::MD_RESERVE;
+        /// Readonly mode.
+        const READONLY = ffi::MD_READONLY;
+        /// Compression features to reduce memory usage.
+        const COMPRESS = ffi::MD_COMPRESS;
+        /// Allow the device to be detached even while it is open.
+        const FORCE = ffi::MD_FORCE;
+        /// For vnode backed devices, avoid `IO_SYNC`.
+        const ASYNC = ffi::MD_ASYNC;
+        /// For vnode backed devices, request verification of the backing file.
+        const VERIFY = ffi::MD_VERIFY;
+        /// For vnode backed devices, cache data in system caches.
+        const CACHE = ffi::MD_CACHE;
+        /// For vnode backed devices, fail `BIO_DELETE` if the file system cannot punch holes.
+        const MUSTDEALLOC = ffi::MD_MUSTDEALLOC;
+    }
+}
+
+impl fmt::Display for MdOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sep = "";
+        for (name, _) in self.iter_names() {
+            write!(f, "{sep}{}", name.to_ascii_lowercase())?;
+            sep = ",";
+        }
+        let unknown = self.bits() & !MdOptions::all().bits();
+        if unknown != 0 {
+            write!(f, "{sep}{unknown:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for MdOptions {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let mut options = MdOptions::empty();
+        for word in s.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+            let (name, negate) = match word.strip_prefix("no") {
+                Some(name) => (name, true),
+                None => (word, false),
+            };
+            let option = if let Some(hex) = word.strip_prefix("0x") {
+                u32::from_str_radix(hex, 16)
+                    .ok()
+                    .map(MdOptions::from_bits_retain)
+            } else if name == "ro" {
+                Some(MdOptions::READONLY)
+            } else {
+                MdOptions::from_name(&name.to_ascii_uppercase())
+            };
+            let option = option.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Unknown md option {word:?}"),
+                )
+            })?;
+            if negate {
+                options.remove(option);
+            } else {
+                options.insert(option);
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Information about an existing md device, as reported by the kernel.
+///
+/// Returned by [`query`] and [`Md::query`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MdInfo {
+    /// Unit number, e.g. the "0" in "md0".
+    pub unit:       u32,
+    /// The kind of backing store.
+    pub md_type:    MdType,
+    /// The backing file, for vnode-backed and preloaded devices.
+    pub file:       Option<PathBuf>,
+    /// Size of the device in bytes.
+    pub mediasize:  u64,
+    /// Sectorsize of the device in bytes.
+    pub sectorsize: u32,
+    /// Options currently in effect.
+    pub options:    MdOptions,
+    /// Base address of the device's storage, as reported in `md_base`.
+    pub base:       u64,
+    /// Synthetic number of heads per cylinder, or 0 if unset.
+    pub fwheads:    i32,
+    /// Synthetic number of sectors per track, or 0 if unset.
+    pub fwsectors:  i32,
+    /// The label associated with the device, if any.
+    pub label:      Option<String>,
+}
+
+impl MdInfo {
+    fn from_mdio(
+        mdio: &ffi::md_ioctl,
+        file: &[u8],
+        label: &[u8],
+    ) -> Result<Self, MdError> {
+        let md_type = MdType::from_raw(mdio.md_type).ok_or_else(|| {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown md type {}", mdio.md_type),
+            );
+            MdError::new(Operation::Query, Some(mdio.md_unit), None, e)
+        })?;
+        let file = cstr_bytes(file);
+        let file = if file.is_empty() {
+            None
+        } else {
+            Some(PathBuf::from(OsStr::from_bytes(file)))
+        };
+        let label = cstr_bytes(label);
+        let label = if label.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(label).into_owned())
+        };
+        Ok(MdInfo {
+            unit: mdio.md_unit,
+            md_type,
+            file,
+            mediasize: mdio.md_mediasize as u64,
+            sectorsize: mdio.md_sectorsize,
+            options: MdOptions::from_bits_retain(mdio.md_options),
+            base: mdio.md_base,
+            fwheads: mdio.md_fwheads,
+            fwsectors: mdio.md_fwsectors,
+            label,
+        })
+    }
+
+    /// The ownership tag within the device's label, if it was created with
+    /// [`Builder::owner_tag`].
+    pub fn owner(&self) -> Option<OwnerTag> {
+        self.label.as_deref().and_then(OwnerTag::from_label)
+    }
+}
+
+/// Query the kernel for information about an existing md device.
+///
+/// The device need not have been created by this process.  Fails with [`MdError::NoSuchUnit`] if
+/// no such unit exists.
+///
+/// # Example
+/// ```no_run
+/// let info = mdconfig::query(0).unwrap();
+/// println!("md0 is {} bytes", info.mediasize);
+/// ```
+pub fn query(unit: u32) -> Result<MdInfo, MdError> {
+    MdControl::open()?.query(unit)
+}
+
+/// List the unit numbers of every md device attached to the system, in ascending order.
+///
+/// This includes devices created by other processes, by
+/// [mdconfig(8)](https://man.freebsd.org/cgi/man.cgi?query=mdconfig), or by the loader.
+///
+/// # Example
+/// ```no_run
+/// for unit in mdconfig::list().unwrap() {
+///     println!("md{unit}");
+/// }
+/// ```
+pub fn list() -> Result<Vec<u32>, MdError> {
+    MdControl::open()?.list_all()
+}
+
+/// Like [`list`], but also [`query`] each device.
+///
+/// Devices that are detached between listing and querying are silently omitted.
+///
+/// # Example
+/// ```no_run
+/// for info in mdconfig::list_info().unwrap() {
+///     println!("md{}\t{:?}\t{}", info.unit, info.md_type, info.mediasize);
+/// }
+/// ```
+pub fn list_info() -> Result<Vec<MdInfo>, MdError> {
+    let mut infos = Vec::new();
+    for unit in list()? {
+        match query(unit) {
+            Ok(info) => infos.push(info),
+            Err(MdError::NoSuchUnit(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(infos)
+}
+
 /// Used to construct a new [`Md`] device.
 ///
 /// Some constructors have required arguments.  Other options can be provided with builder methods.
@@ -67,12 +401,23 @@ macro_rules! set_bool {
 /// ```
 #[derive(Debug)]
 pub struct Builder {
-    filename: Option<PathBuf>,
-    label:    Option<Vec<u8>>,
-    mdio:     ffi::md_ioctl,
+    filename:      Option<PathBuf>,
+    label:         Option<Vec<u8>>,
+    owner_tag:     bool,
+    mdio:          ffi::md_ioctl,
+    /// Set by [`Builder::size_str`].  Resolved once the sectorsize is known.
+    size:          Option<Result<Size, String>>,
+    drop_policy:   DropPolicy,
+    on_drop_error: Option<DropErrorHook>,
+    /// Set by [`Builder::unit_range`].
+    unit_range:    Option<Range<u32>>,
+    wait_ready:    bool,
 }
 
 impl Builder {
+    /// How long [`Builder::wait_ready`] waits for a new device to become ready.
+    pub const WAIT_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
     fn new() -> Self {
         let mdio = ffi::md_ioctl {
             md_version:    ffi::MDIOVERSION,
@@ -92,6 +437,12 @@ impl Builder {
             mdio,
             filename: None,
             label: None,
+            owner_tag: false,
+            size: None,
+            drop_policy: DropPolicy::default(),
+            on_drop_error: None,
+            unit_range: None,
+            wait_ready: false,
         }
     }
 
@@ -192,6 +543,29 @@ impl Builder {
         self
     }
 
+    /// Enable/disable clustering of I/O requests.
+    ///
+    /// The default is enabled for vnode and swap backed devices.
+    pub fn cluster(mut self, cluster: bool) -> Self {
+        set_bool!(self.mdio.md_options, cluster, ffi::MD_CLUSTER);
+        self
+    }
+
+    /// Choose what the resulting [`Md`] will do with the device when dropped.
+    ///
+    /// See [`DropPolicy`].  The default is [`DropPolicy::Force`].
+    pub fn drop_policy(mut self, policy: DropPolicy) -> Self {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Allow the device to be detached even while it is open, or shrunk without the `force`
+    /// argument to [`Md::resize`].
+    pub fn force(mut self, force: bool) -> Self {
+        set_bool!(self.mdio.md_options, force, ffi::MD_FORCE);
+        self
+    }
+
     /// Construct a specific synthetic geometry, for malloc and vnode backed devices.
     ///
     /// This is useful for constructing bootable images for later download to other devices.
@@ -200,14 +574,23 @@ impl Builder {
         self
     }
 
+    /// Report errors encountered while dropping the resulting [`Md`] to `hook`, instead of
+    /// panicking.
+    ///
+    /// See [`Md::on_drop_error`].
+    pub fn on_drop_error<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&MdError) + Send + Sync + 'static,
+    {
+        self.on_drop_error = Some(DropErrorHook(Arc::new(hook)));
+        self
+    }
+
     /// Associate an arbitrary string with the new memory disk.
     ///
     /// The label will be reported by `mdconfig -lv`.
     pub fn label(mut self, label: &str) -> Self {
-        let mut clabel = Vec::with_capacity(libc::PATH_MAX as usize);
-        clabel.extend_from_slice(OsStr::new(label).as_bytes());
-        clabel.resize(libc::PATH_MAX as usize, 0);
-        self.label = Some(clabel);
+        self.label = Some(label.as_bytes().to_vec());
         self
     }
 
@@ -222,6 +605,43 @@ impl Builder {
         self
     }
 
+    /// Replace all options at once.
+    ///
+    /// This overrides the defaults chosen by the constructor, as well as any options set by earlier
+    /// builder methods.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use mdconfig::MdOptions;
+    /// let md = mdconfig::Builder::swap(1 << 20)
+    ///     .options("cluster,reserve".parse().unwrap())
+    ///     .create()
+    ///     .unwrap();
+    /// ```
+    pub fn options(mut self, options: MdOptions) -> Self {
+        self.mdio.md_options &= ffi::MD_AUTOUNIT;
+        self.mdio.md_options |= options.bits();
+        self
+    }
+
+    /// Append an [`OwnerTag`] identifying this process to the device's label.
+    ///
+    /// If this process dies without dropping the resulting [`Md`], for example because it was
+    /// killed by `SIGKILL`, the device can later be cleaned up by [`reap_orphans`].
+    ///
+    /// # Example
+    /// ```no_run
+    /// let md = mdconfig::Builder::malloc(1 << 20)
+    ///     .owner_tag(true)
+    ///     .create()
+    ///     .unwrap();
+    /// assert!(md.query().unwrap().owner().unwrap().is_alive());
+    /// ```
+    pub fn owner_tag(mut self, owner_tag: bool) -> Self {
+        self.owner_tag = owner_tag;
+        self
+    }
+
     /// Allocate and reserve all needed storage from the start, rather than as needed.
     pub fn reserve(mut self, reserve: bool) -> Self {
         set_bool!(self.mdio.md_options, reserve, ffi::MD_RESERVE);
@@ -253,18 +673,95 @@ impl Builder {
     /// This can be used to override the automatically detected size for a vnode-backed Md.
     pub fn size(mut self, size: libc::off_t) -> Self {
         self.mdio.md_mediasize = size;
+        self.size = None;
+        self
+    }
+
+    /// Set the size of the created device, using the same syntax as `mdconfig -s`.
+    ///
+    /// See [`Size`] for the syntax.  A count of sectors will be resolved against the device's
+    /// sectorsize, regardless of whether [`Builder::sectorsize`] is called before or after this
+    /// method.  An unparseable size will be reported by [`Builder::validate`].
+    ///
+    /// # Example
+    /// ```no_run
+    /// let md = mdconfig::Builder::swap(0)
+    ///     .size_str("512m")
+    ///     .create()
+    ///     .unwrap();
+    /// ```
+    pub fn size_str(mut self, size: &str) -> Self {
+        self.size = Some(size.parse::<Size>().map_err(|_| size.to_owned()));
         self
     }
 
     /// Request a specific unit number for the new device.
     ///
-    /// The default is to automatically assign a unit number.
+    /// The default is to automatically assign a unit number.  Overrides any earlier
+    /// [`Builder::unit_range`].
     pub fn unit(mut self, unit: u32) -> Self {
         self.mdio.md_unit = unit;
         self.mdio.md_options &= !ffi::MD_AUTOUNIT;
+        self.unit_range = None;
+        self
+    }
+
+    /// Use the lowest free unit number within `range` for the new device.
+    ///
+    /// Each unit in the range is tried in order, skipping those that are already in use.  If
+    /// every unit is in use, creation fails with [`MdError::UnitInUse`].  This is useful for
+    /// parallel jobs that each want predictable device names without colliding with each other.
+    /// Overrides any earlier [`Builder::unit`].
+    ///
+    /// # Example
+    /// ```no_run
+    /// let md = mdconfig::Builder::malloc(1 << 20)
+    ///     .unit_range(1000..2000)
+    ///     .create()
+    ///     .unwrap();
+    /// assert!(md.unit() >= 1000);
+    /// ```
+    pub fn unit_range(mut self, range: Range<u32>) -> Self {
+        self.mdio.md_unit = 0;
+        self.mdio.md_options |= ffi::MD_AUTOUNIT;
+        self.unit_range = Some(range);
         self
     }
 
+    /// Like [`Builder::unit_range`], but choose a subrange based on an environment variable.
+    ///
+    /// `range` is divided into chunks of `width` units each.  If the environment variable `var`
+    /// contains an integer, like a CI job id or nextest's `NEXTEST_TEST_GLOBAL_SLOT`, then that
+    /// integer, modulo the number of chunks, selects the chunk to use.  Otherwise, the whole range
+    /// is used.
+    ///
+    /// # Example
+    /// ```no_run
+    /// // Slot 0 uses md1000 through md1009, slot 1 uses md1010 through md1019, etc.
+    /// let md = mdconfig::Builder::malloc(1 << 20)
+    ///     .unit_range_from_env("NEXTEST_TEST_GLOBAL_SLOT", 1000..2000, 10)
+    ///     .create()
+    ///     .unwrap();
+    /// ```
+    pub fn unit_range_from_env(
+        self,
+        var: &str,
+        range: Range<u32>,
+        width: u32,
+    ) -> Self {
+        let chunks = range.len() as u64 / u64::from(width.max(1));
+        let slot = std::env::var(var)
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        match slot {
+            Some(slot) if chunks > 0 => {
+                let start = range.start + (slot % chunks) as u32 * width;
+                self.unit_range(start..start + width)
+            }
+            _ => self.unit_range(range),
+        }
+    }
+
     /// For vnode backed devices: enable/disable requesting verification of the file used for
     /// backing store.
     pub fn verify(mut self, verify: bool) -> Self {
@@ -272,32 +769,318 @@ impl Builder {
         self
     }
 
+    /// Wait for the new device to be ready before returning it.
+    ///
+    /// See [`Md::wait_ready`].  Waits for up to [`Builder::WAIT_READY_TIMEOUT`].  If the device
+    /// doesn't become ready in time, it will be forcefully detached regardless of its
+    /// [`DropPolicy`], and creation will fail with [`MdError::TimedOut`].
+    pub fn wait_ready(mut self, wait_ready: bool) -> Self {
+        self.wait_ready = wait_ready;
+        self
+    }
+
+    /// Check the configuration for problems that the kernel would reject, or silently ignore.
+    ///
+    /// This is done automatically by [`Builder::create`], but may also be done explicitly.  It
+    /// does not require any privileges, or even the md driver.  On failure, it returns
+    /// [`MdError::InvalidConfig`], which lists every problem found.
+    ///
+    /// # Example
+    /// ```
+    /// # use mdconfig::{Builder, ConfigProblem, MdError};
+    /// let e = Builder::malloc(1000).sectorsize(3).validate().unwrap_err();
+    /// let MdError::InvalidConfig(_, problems) = e else { panic!() };
+    /// assert_eq!(problems[0], ConfigProblem::SectorsizeNotPowerOfTwo(3));
+    /// ```
+    pub fn validate(&self) -> Result<(), MdError> {
+        let mdio = &self.mdio;
+        let mut problems = Vec::new();
+        let sectorsize = self.sectorsize_or_default();
+        if !sectorsize.is_power_of_two() {
+            problems.push(ConfigProblem::SectorsizeNotPowerOfTwo(sectorsize));
+        }
+        let maxphys = maxphys();
+        if u64::from(sectorsize) > maxphys {
+            problems.push(ConfigProblem::SectorsizeTooLarge {
+                sectorsize,
+                maxphys,
+            });
+        }
+        let vnode = mdio.md_type == ffi::md_types_MD_VNODE;
+        match self.mediasize() {
+            Err(problem) => problems.push(problem),
+            // For vnode-backed devices, a size of 0 means to use the size of the file.
+            Ok(0) if vnode => (),
+            Ok(mediasize) if mediasize <= 0 => {
+                problems.push(ConfigProblem::SizeNotPositive(mediasize));
+            }
+            Ok(mediasize) => {
+                if sectorsize.is_power_of_two()
+                    && mediasize % i64::from(sectorsize) != 0
+                {
+                    problems.push(ConfigProblem::SizeNotMultipleOfSectorsize {
+                        size: mediasize as u64,
+                        sectorsize,
+                    });
+                }
+                let page_size = page_size();
+                if mdio.md_type == ffi::md_types_MD_SWAP
+                    && mediasize as u64 % page_size != 0
+                {
+                    problems.push(ConfigProblem::SizeNotMultipleOfPageSize {
+                        size: mediasize as u64,
+                        page_size,
+                    });
+                }
+            }
+        }
+        if (mdio.md_fwheads == 0) != (mdio.md_fwsectors == 0) {
+            problems.push(ConfigProblem::IncompleteGeometry);
+        }
+        if mdio.md_fwheads < 0 || mdio.md_fwsectors < 0 {
+            problems.push(ConfigProblem::NegativeGeometry);
+        }
+        let vnode_only = MdOptions::from_bits_truncate(mdio.md_options)
+            & (MdOptions::ASYNC
+                | MdOptions::CACHE
+                | MdOptions::MUSTDEALLOC
+                | MdOptions::VERIFY);
+        if !vnode && !vnode_only.is_empty() {
+            problems.push(ConfigProblem::VnodeOnlyOptions(vnode_only));
+        }
+        if self.unit_range.as_ref().is_some_and(Range::is_empty) {
+            problems.push(ConfigProblem::EmptyUnitRange);
+        }
+        if self
+            .full_label()
+            .is_some_and(|l| l.len() >= libc::PATH_MAX as usize)
+        {
+            problems.push(ConfigProblem::LabelTooLong);
+        }
+        if self
+            .filename
+            .as_ref()
+            .is_some_and(|f| f.as_os_str().len() >= libc::PATH_MAX as usize)
+        {
+            problems.push(ConfigProblem::PathTooLong);
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(MdError::invalid_config(
+                self.requested_unit(),
+                self.filename.as_deref(),
+                problems,
+            ))
+        }
+    }
+
+    /// The size of the device in bytes, resolving any size set by [`Builder::size_str`].
+    fn mediasize(&self) -> Result<libc::off_t, ConfigProblem> {
+        match &self.size {
+            None => Ok(self.mdio.md_mediasize),
+            Some(Err(s)) => Err(ConfigProblem::InvalidSize(s.clone())),
+            Some(Ok(size)) => size
+                .bytes(self.sectorsize_or_default())
+                .and_then(|bytes| libc::off_t::try_from(bytes).ok())
+                .ok_or(ConfigProblem::SizeOverflow(*size)),
+        }
+    }
+
+    /// The sectorsize that the kernel will use.
+    fn sectorsize_or_default(&self) -> u32 {
+        match self.mdio.md_sectorsize {
+            0 => 512,
+            sectorsize => sectorsize,
+        }
+    }
+
+    /// The label to apply, including the ownership tag if requested.
+    fn full_label(&self) -> Option<Vec<u8>> {
+        let mut label = self.label.clone();
+        if self.owner_tag {
+            let label = label.get_or_insert_with(Vec::new);
+            if !label.is_empty() {
+                label.push(b' ');
+            }
+            label.extend_from_slice(OwnerTag::current().to_string().as_bytes());
+        }
+        label
+    }
+
+    /// The explicitly requested unit number, if any.
+    fn requested_unit(&self) -> Option<u32> {
+        (self.mdio.md_options & ffi::MD_AUTOUNIT == 0)
+            .then_some(self.mdio.md_unit)
+    }
+
     /// Finalize the Builder into an [`Md`] device.
-    pub fn create(mut self) -> io::Result<Md> {
-        let devmd = fs::File::open("/dev/mdctl")?;
+    ///
+    /// The configuration is first checked with [`Builder::validate`].
+    pub fn create(self) -> Result<Md, MdError> {
+        self.validate()?;
+        self.attach(&MdControl::open()?, None)
+    }
+
+    /// Attach a new device, after it has been validated.  If `retain` is set, the new `Md` will
+    /// use it for all further operations, including any cleanup if this fails.
+    fn attach(
+        mut self,
+        ctl: &MdControl,
+        retain: Option<Arc<MdControl>>,
+    ) -> Result<Md, MdError> {
+        if let Ok(mediasize) = self.mediasize() {
+            self.mdio.md_mediasize = mediasize;
+        }
+        let unit = self.requested_unit();
         let mut _storage = None;
-        if let Some(filename) = self.filename {
-            let md = fs::metadata(&filename)?;
+        if let Some(filename) = &self.filename {
+            let md = fs::metadata(filename).map_err(|e| {
+                MdError::new(Operation::Attach, unit, Some(filename), e)
+            })?;
             if self.mdio.md_mediasize == 0 {
                 self.mdio.md_mediasize = md.size() as libc::off_t;
             }
             let mut v = Vec::with_capacity(libc::PATH_MAX as usize);
-            v.extend_from_slice(OsStr::new(&filename).as_bytes());
+            v.extend_from_slice(OsStr::new(filename).as_bytes());
             v.resize(libc::PATH_MAX as usize, 0);
             self.mdio.md_file = v.as_mut_ptr() as *mut libc::c_char;
             _storage = Some(v);
         }
-        if let Some(label) = self.label.as_mut() {
+        let mut _label = self.full_label().map(|mut label| {
+            label.resize(libc::PATH_MAX as usize, 0);
+            label
+        });
+        if let Some(label) = _label.as_mut() {
             self.mdio.md_label = label.as_mut_ptr() as *mut libc::c_char;
         }
-        unsafe { ioctl::mdiocattach(devmd.as_raw_fd(), &mut self.mdio)? };
-        let name = format!("md{}", self.mdio.md_unit);
-        let path = Path::new("/dev").join(&name);
-        Ok(Md {
-            name,
-            path,
-            unit: self.mdio.md_unit,
-        })
+        let unit = match self.unit_range.clone() {
+            Some(range) => {
+                self.mdio.md_options &= !ffi::MD_AUTOUNIT;
+                let mut r = None;
+                for unit in range {
+                    self.mdio.md_unit = unit;
+                    let filename = self.filename.as_deref();
+                    r = Some(ctl.attach(&mut self.mdio, Some(unit), filename));
+                    if !matches!(r, Some(Err(MdError::UnitInUse(_)))) {
+                        break;
+                    }
+                }
+                r.expect("Builder::validate rejects empty unit ranges")?
+            }
+            None => {
+                ctl.attach(&mut self.mdio, unit, self.filename.as_deref())?
+            }
+        };
+        // Prefer the kernel's view, which fills in defaults.
+        let info = ctl.query(unit).or_else(|_| {
+            let file = _storage.as_deref().unwrap_or_default();
+            let label = _label.as_deref().unwrap_or_default();
+            MdInfo::from_mdio(&self.mdio, file, label).map(|mut info| {
+                info.sectorsize = self.sectorsize_or_default();
+                info.options =
+                    MdOptions::from_bits_truncate(info.options.bits());
+                info
+            })
+        });
+        let info = match info {
+            Ok(info) => info,
+            Err(e) => {
+                // Don't leak a device that can't be returned.
+                let _ = ctl.detach(unit, true);
+                return Err(e);
+            }
+        };
+        let mut md = Md::new(&info, true);
+        md.ctl = retain;
+        md.registration = registry::register(unit);
+        md.drop_policy = self.drop_policy;
+        md.on_drop_error = self.on_drop_error.take();
+        if self.wait_ready {
+            if let Err(e) = md.wait_ready(Builder::WAIT_READY_TIMEOUT) {
+                // Detach it now, because the drop policy might leave it attached.
+                let _ = md.detach(true);
+                md.owned = false;
+                return Err(e);
+            }
+        }
+        Ok(md)
+    }
+}
+
+/// What an [`Md`] should do with its device when dropped.
+///
+/// Newly created devices are asynchronously opened by GEOM tasters, so a non-forced detach may
+/// fail with [`MdError::Busy`] for a short while after creation.
+///
+/// # Example
+/// ```no_run
+/// # use std::time::Duration;
+/// # use mdconfig::{Builder, DropPolicy};
+/// let md = Builder::malloc(1 << 20)
+///     .drop_policy(DropPolicy::TryThenForce(Duration::from_secs(1)))
+///     .create()
+///     .unwrap();
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum DropPolicy {
+    /// Forcefully detach the device, regardless of whether anything else is using it.
+    #[default]
+    Force,
+    /// Try to detach the device without force, retrying for as long as it is busy, up to the given
+    /// grace period.  Then detach it forcefully.
+    TryThenForce(Duration),
+    /// Try once to detach the device without force.  If it's busy, leave it attached.
+    TryOnly,
+    /// Leave the device attached.
+    Leak,
+}
+
+/// Controls how [`Md::destroy_with`] retries a busy device.
+///
+/// Between attempts, it sleeps for a delay that starts at `initial` and doubles after each attempt,
+/// up to `max`.  By default, the delay starts at 10ms and grows to at most 500ms.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct DestroyOptions {
+    timeout:       Duration,
+    initial_delay: Duration,
+    max_delay:     Duration,
+    force:         bool,
+}
+
+impl DestroyOptions {
+    /// Retry for up to `timeout`, with the default backoff and no force fallback.
+    pub fn new(timeout: Duration) -> Self {
+        DestroyOptions {
+            timeout,
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(500),
+            force: false,
+        }
+    }
+
+    /// Set the delay before the first retry, and the maximum delay between retries.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_delay = initial;
+        self.max_delay = max;
+        self
+    }
+
+    /// If the device is still busy when the timeout expires, detach it forcefully.
+    pub fn force_on_timeout(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+}
+
+/// A callback for errors encountered while dropping an [`Md`].
+#[derive(Clone)]
+struct DropErrorHook(Arc<dyn Fn(&MdError) + Send + Sync>);
+
+impl fmt::Debug for DropErrorHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DropErrorHook")
     }
 }
 
@@ -306,8 +1089,9 @@ impl Builder {
 /// Note that this represents the device itself, not an open device.  To open it, first create it
 /// and then open it like any other file.
 ///
-/// During Drop, the device will be forcefully detached, regardless of whether any other process is
-/// using it.  To conditionally detach the device only if it is idle, use [`Md::try_destroy`].
+/// During Drop, the device will by default be forcefully detached, regardless of whether any other
+/// process is using it.  That can be changed with [`Md::set_drop_policy`].  To conditionally detach
+/// the device only if it is idle, use [`Md::try_destroy`].
 ///
 /// # Example
 /// ```no_run
@@ -316,33 +1100,287 @@ impl Builder {
 /// ```
 #[derive(Debug)]
 pub struct Md {
-    name: String,
+    name:          String,
     /// Path to the md device.  e.g. /dev/md0
-    path: PathBuf,
+    path:          PathBuf,
     /// Unit number
-    unit: u32,
+    unit:          u32,
+    /// Should the device be detached on Drop?
+    owned:         bool,
+    /// The device's slot in the cleanup registry, if it's registered.
+    registration:  Option<usize>,
+    /// The process that owns the device.  Other processes, such as forked children, must not
+    /// detach it on Drop.
+    pid:           u32,
+    /// Used for all operations, if set.  Otherwise, `/dev/mdctl` is opened as needed.
+    ctl:           Option<Arc<MdControl>>,
+    drop_policy:   DropPolicy,
+    /// Called with any error encountered during Drop.  If unset, such errors panic.
+    on_drop_error: Option<DropErrorHook>,
+    /// Size in bytes, kept current by [`Md::resize`].
+    size:          AtomicU64,
+    sectorsize:    u32,
+    md_type:       MdType,
+    options:       MdOptions,
+    backing_file:  Option<PathBuf>,
 }
 
 impl Md {
-    fn detach(&mut self, force: bool) -> io::Result<()> {
-        let md_options = if force { ffi::MD_FORCE } else { 0 };
-        let mut mdio = ffi::md_ioctl {
-            md_version: ffi::MDIOVERSION,
-            md_unit: self.unit,
-            md_type: 0,
-            md_file: ptr::null_mut(),
-            md_mediasize: 0,
-            md_sectorsize: 0,
-            md_options,
-            md_base: 0,
-            md_fwheads: 0,
-            md_fwsectors: 0,
-            md_label: ptr::null_mut(),
-            md_pad: [0; ffi::MDNPAD as usize],
+    fn new(info: &MdInfo, owned: bool) -> Self {
+        let unit = info.unit;
+        let name = format!("md{unit}");
+        let path = Path::new("/dev").join(&name);
+        Md {
+            name,
+            path,
+            unit,
+            owned,
+            size: AtomicU64::new(info.mediasize),
+            sectorsize: info.sectorsize,
+            md_type: info.md_type,
+            options: info.options,
+            backing_file: info.file.clone(),
+            registration: None,
+            pid: std::process::id(),
+            ctl: None,
+            drop_policy: DropPolicy::default(),
+            on_drop_error: None,
+        }
+    }
+
+    /// Perform an operation with the md control device.
+    fn control<T>(
+        &self,
+        f: impl FnOnce(&MdControl) -> Result<T, MdError>,
+    ) -> Result<T, MdError> {
+        match &self.ctl {
+            Some(ctl) => f(ctl),
+            None => f(&MdControl::open()?),
+        }
+    }
+
+    fn detach(&mut self, force: bool) -> Result<(), MdError> {
+        self.control(|ctl| ctl.detach(self.unit, force))
+    }
+
+    /// Try to detach the device without force, retrying while it's busy, as directed by `opts`.
+    fn detach_retrying(
+        &mut self,
+        opts: &DestroyOptions,
+    ) -> Result<(), MdError> {
+        let start = Instant::now();
+        let mut delay = opts.initial_delay;
+        loop {
+            match self.detach(false) {
+                Err(MdError::Busy(_)) if start.elapsed() < opts.timeout => {
+                    let remaining =
+                        opts.timeout.saturating_sub(start.elapsed());
+                    thread::sleep(delay.min(remaining));
+                    delay = delay.saturating_mul(2).min(opts.max_delay);
+                }
+                Err(MdError::Busy(_)) if opts.force => {
+                    return self.detach(true);
+                }
+                r => return r,
+            }
+        }
+    }
+
+    /// Destroy the device, retrying for up to `timeout` while it's busy.
+    ///
+    /// Newly created devices are briefly opened by GEOM tasters, so [`Md::try_destroy`] will often
+    /// fail with [`MdError::Busy`] immediately after creation.  This method retries non-forced
+    /// detaches, with exponential backoff, for as long as the device is busy.  Any other error is
+    /// returned immediately.  On timeout, the `Md` is returned along with the last error.
+    ///
+    /// See [`Md::destroy_with`] to customize the backoff.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// let md = mdconfig::Builder::swap(1 << 20).create().unwrap();
+    /// md.destroy_timeout(Duration::from_secs(5)).unwrap();
+    /// ```
+    pub fn destroy_timeout(
+        self,
+        timeout: Duration,
+    ) -> std::result::Result<(), (Self, MdError)> {
+        self.destroy_with(&DestroyOptions::new(timeout))
+    }
+
+    /// Like [`Md::destroy_timeout`], but with customizable retry behavior.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use mdconfig::DestroyOptions;
+    /// let md = mdconfig::Builder::swap(1 << 20).create().unwrap();
+    /// let opts = DestroyOptions::new(Duration::from_secs(5))
+    ///     .backoff(Duration::from_millis(1), Duration::from_millis(100))
+    ///     .force_on_timeout(true);
+    /// md.destroy_with(&opts).unwrap();
+    /// ```
+    pub fn destroy_with(
+        mut self,
+        opts: &DestroyOptions,
+    ) -> std::result::Result<(), (Self, MdError)> {
+        match self.detach_retrying(opts) {
+            Ok(()) => {
+                self.owned = false;
+                Ok(())
+            }
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    /// Report the policy that will be applied on Drop.
+    pub fn drop_policy(&self) -> DropPolicy {
+        self.drop_policy
+    }
+
+    /// Take ownership of an existing md device, such as one created by
+    /// [mdconfig(8)](https://man.freebsd.org/cgi/man.cgi?query=mdconfig).
+    ///
+    /// Like any other `Md`, the device will be detached on Drop.  To merely use it without taking
+    /// ownership, use [`MdRef::from_unit`] instead.  Preloaded devices cannot be owned, and fail
+    /// with [`MdError::WrongType`]; use [`PreloadedMd`] for those.
+    ///
+    /// # Example
+    /// ```no_run
+    /// let md = mdconfig::Md::from_unit(7).unwrap();
+    /// assert_eq!(md.name(), "md7");
+    /// ```
+    pub fn from_unit(unit: u32) -> Result<Self, MdError> {
+        let info = query(unit)?;
+        if info.md_type == MdType::Preload {
+            return Err(MdError::wrong_type(
+                Operation::Own,
+                unit,
+                format!("md{unit} is preloaded, and cannot be owned"),
+            ));
+        }
+        Ok(Md::new(&info, true))
+    }
+
+    /// Like [`Md::from_unit`], but takes the device's path, like "/dev/md7".
+    pub fn from_path(path: &Path) -> Result<Self, MdError> {
+        Md::from_unit(unit_from_path(path)?)
+    }
+
+    /// Disarm the automatic detach on Drop, returning a token that can later be used to
+    /// [`reclaim`](Md::reclaim) the device.
+    ///
+    /// The device will remain attached after this process exits.  This is useful for tools that
+    /// create a device in one command and destroy it in another.
+    ///
+    /// md(4) can't change a device's label after it's attached, so a device created with
+    /// [`Builder::owner_tag`] keeps its tag, and [`reap_orphans`] will still detach it once this
+    /// process exits.  Don't combine the two unless that's what you want.
+    pub fn into_persistent(
+        mut self,
+    ) -> std::result::Result<MdToken, (Self, MdError)> {
+        match self.query() {
+            Ok(info) => {
+                self.owned = false;
+                Ok(MdToken::new(info))
+            }
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    /// Report errors encountered during Drop to `hook`, instead of panicking.
+    ///
+    /// Without a hook, a failure to detach the device during Drop will panic, unless the thread is
+    /// already panicking, or the policy is [`DropPolicy::TryOnly`] and the device was merely busy.
+    /// With a hook, Drop never panics.  That's useful for long-running daemons, where a device
+    /// might be destroyed out from under them by an administrator.
+    ///
+    /// # Example
+    /// ```no_run
+    /// let mut md = mdconfig::Builder::malloc(1 << 20).create().unwrap();
+    /// md.on_drop_error(|e| eprintln!("Could not detach: {e}"));
+    /// ```
+    pub fn on_drop_error<F>(&mut self, hook: F)
+    where
+        F: Fn(&MdError) + Send + Sync + 'static,
+    {
+        self.on_drop_error = Some(DropErrorHook(Arc::new(hook)));
+    }
+
+    /// The device's backing file, if it's vnode-backed or preloaded.
+    ///
+    /// This is the path as it was given to [`Builder::vnode`], which may be relative.
+    pub fn backing_file(&self) -> Option<&Path> {
+        self.backing_file.as_deref()
+    }
+
+    /// How many bytes of storage the backing file of a vnode-backed device occupies, or `None`
+    /// for other types of device.
+    ///
+    /// This can be compared to [`Md::size`] to see how sparse the file is, for example after
+    /// [`MdDisk::delete`].  Note that some file systems, notably ZFS, update this lazily.  After
+    /// deleting, it may take a few seconds to change.
+    pub fn backing_allocated(&self) -> Result<Option<u64>, MdError> {
+        let Some(file) = self.backing_file.as_deref() else {
+            return Ok(None);
         };
-        let mddev = fs::File::open("/dev/mdctl")?;
-        unsafe { ioctl::mdiocdetach(mddev.as_raw_fd(), &mut mdio) }?;
-        Ok(())
+        let md = fs::metadata(file).map_err(|e| {
+            MdError::new(Operation::StatFile, Some(self.unit), Some(file), e)
+        })?;
+        Ok(Some(md.blocks() * 512))
+    }
+
+    /// Copy the device's contents to a new file at `path`, overwriting it if it exists.
+    ///
+    /// All-zero regions are left as holes, so the file is only as large as the data on the
+    /// device.  This is useful for capturing the state of a malloc or swap-backed device after a
+    /// test failure.
+    ///
+    /// # Example
+    /// ```no_run
+    /// let md = mdconfig::Builder::malloc(1 << 32).create().unwrap();
+    /// // ... run a test that fails ...
+    /// md.export_to("/tmp/md.img").unwrap();
+    /// ```
+    pub fn export_to<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<ExportStats, MdError> {
+        self.export_with(path, &ExportOptions::default(), |_, _| ())
+    }
+
+    /// Like [`Md::export_to`], but with customizable options.  `progress` is called after each
+    /// chunk with the number of bytes copied so far and the total.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use mdconfig::ExportOptions;
+    /// let md = mdconfig::Builder::swap(1 << 32).create().unwrap();
+    /// let opts = ExportOptions::default().truncate_trailing_zeros(true);
+    /// let stats = md
+    ///     .export_with("/tmp/md.img", &opts, |done, total| {
+    ///         eprint!("\r{}%", done * 100 / total);
+    ///     })
+    ///     .unwrap();
+    /// println!("\nwrote {} bytes", stats.bytes_written);
+    /// ```
+    pub fn export_with<P, F>(
+        &self,
+        path: P,
+        opts: &ExportOptions,
+        progress: F,
+    ) -> Result<ExportStats, MdError>
+    where
+        P: AsRef<Path>,
+        F: FnMut(u64, u64),
+    {
+        let disk = self.open(fs::OpenOptions::new().read(true))?;
+        export::export(&disk, path.as_ref(), opts, progress)
+    }
+
+    /// The kind of backing store.
+    pub fn md_type(&self) -> MdType {
+        self.md_type
     }
 
     /// Report the name to the device, like "md0".
@@ -350,55 +1388,293 @@ impl Md {
         &self.name
     }
 
+    /// Open the device itself, for I/O and disk ioctls.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::fs::OpenOptions;
+    /// let md = mdconfig::Builder::malloc(1 << 20).create().unwrap();
+    /// let disk = md.open(OpenOptions::new().read(true).write(true)).unwrap();
+    /// assert_eq!(disk.mediasize().unwrap(), 1 << 20);
+    /// ```
+    pub fn open(&self, opts: &fs::OpenOptions) -> Result<MdDisk, MdError> {
+        MdDisk::open(self, opts)
+    }
+
+    /// The options in effect when this `Md` was created or obtained.
+    pub fn options(&self) -> MdOptions {
+        self.options
+    }
+
     /// Report the path to the device, like "/dev/md0".
     pub fn path(&self) -> &Path {
         self.path.as_path()
     }
 
+    /// Query the kernel for the device's current configuration.
+    ///
+    /// # Example
+    /// ```no_run
+    /// let md = mdconfig::Builder::malloc(1 << 20).create().unwrap();
+    /// assert_eq!(md.query().unwrap().mediasize, 1 << 20);
+    /// ```
+    pub fn query(&self) -> Result<MdInfo, MdError> {
+        self.control(|ctl| ctl.query(self.unit))
+    }
+
+    /// Take ownership of a device previously released by [`Md::into_persistent`].
+    ///
+    /// Fails if the device no longer exists, or if its type, size, sectorsize, backing file, or
+    /// label no longer match the token.  On success, the device will once again be detached on
+    /// Drop.
+    pub fn reclaim(token: &MdToken) -> Result<Self, MdError> {
+        let info = query(token.unit())?;
+        token.verify(&info)?;
+        Ok(Md::new(&info, true))
+    }
+
     /// Change the device's size in bytes.
     ///
     /// If the new size is less than the old size, the `force` option must be used, and data may be
-    /// discarded.
-    pub fn resize(&self, newsize: libc::off_t, force: bool) -> io::Result<()> {
-        let mut mdio = ffi::md_ioctl {
-            md_version:    ffi::MDIOVERSION,
-            md_unit:       self.unit,
-            md_type:       0,
-            md_file:       ptr::null_mut(),
-            md_mediasize:  newsize,
-            md_sectorsize: 0,
-            md_options:    0,
-            md_base:       0,
-            md_fwheads:    0,
-            md_fwsectors:  0,
-            md_label:      ptr::null_mut(),
-            md_pad:        [0; ffi::MDNPAD as usize],
+    /// discarded.  Otherwise, the resize will fail with [`MdError::ResizeNeedsForce`].
+    pub fn resize(
+        &self,
+        newsize: libc::off_t,
+        force: bool,
+    ) -> Result<(), MdError> {
+        self.control(|ctl| ctl.resize(self.unit, newsize, force))?;
+        self.size.store(newsize as u64, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Change the device's size in bytes, along with the size of its backing file.
+    ///
+    /// For a vnode-backed device, [`Md::resize`] changes only the size of the device.  Growing the
+    /// device past the end of its backing file yields a device whose tail can't be used, and
+    /// shrinking the device leaves the file as large as before.  Instead, this method extends the
+    /// backing file as directed by `growth` before growing the device, or truncates the backing
+    /// file after shrinking the device.  For other types of device, it's equivalent to
+    /// [`Md::resize`].
+    ///
+    /// `newsize` must be a positive multiple of the sectorsize, and shrinking requires `force`
+    /// unless the device was created with [`Builder::force`].  Both are checked before anything is
+    /// changed.  If the backing file was specified with a relative path, it's resolved relative to
+    /// the current directory.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::path::Path;
+    /// # use mdconfig::FileGrowth;
+    /// let md = mdconfig::Builder::vnode(Path::new("/tmp/disk.img"))
+    ///     .create()
+    ///     .unwrap();
+    /// md.resize_with_file(1 << 30, false, FileGrowth::Sparse).unwrap();
+    /// ```
+    pub fn resize_with_file(
+        &self,
+        newsize: libc::off_t,
+        force: bool,
+        growth: FileGrowth,
+    ) -> Result<(), MdError> {
+        let info = self.query()?;
+        self.resize_info(&info, newsize, force, growth)
+    }
+
+    /// Grow the device by at least `bytes`, rounded up to a multiple of the sectorsize.  For
+    /// swap-backed devices, it's also rounded up to a multiple of the page size.
+    ///
+    /// Vnode-backed devices' backing files are extended too, as with [`Md::resize_with_file`].
+    /// Returns the new size.
+    pub fn grow_by(
+        &self,
+        bytes: u64,
+        growth: FileGrowth,
+    ) -> Result<u64, MdError> {
+        let info = self.query()?;
+        let newsize = round_up(bytes, size_granularity(&info))
+            .and_then(|delta| info.mediasize.checked_add(delta))
+            .filter(|newsize| *newsize <= libc::off_t::MAX as u64)
+            .ok_or_else(|| {
+                let problem = ConfigProblem::SizeOverflow(Size::Bytes(bytes));
+                MdError::invalid_config(Some(self.unit), None, vec![problem])
+            })?;
+        self.resize_info(&info, newsize as libc::off_t, false, growth)?;
+        Ok(newsize)
+    }
+
+    /// Shrink the device by at least `bytes`, rounded up to a multiple of the sectorsize.  For
+    /// swap-backed devices, it's also rounded up to a multiple of the page size.
+    ///
+    /// Vnode-backed devices' backing files are truncated too, as with [`Md::resize_with_file`].
+    /// Returns the new size.  Fails with [`MdError::InvalidConfig`] if that would leave nothing.
+    pub fn shrink_by(&self, bytes: u64, force: bool) -> Result<u64, MdError> {
+        let info = self.query()?;
+        let invalid = |problem| {
+            MdError::invalid_config(Some(self.unit), None, vec![problem])
         };
-        if force {
-            mdio.md_options |= ffi::MD_FORCE;
+        let delta =
+            round_up(bytes, size_granularity(&info)).ok_or_else(|| {
+                invalid(ConfigProblem::SizeOverflow(Size::Bytes(bytes)))
+            })?;
+        let newsize = info
+            .mediasize
+            .checked_sub(delta)
+            .filter(|newsize| *newsize > 0)
+            .ok_or_else(|| {
+                let newsize = i128::from(info.mediasize) - i128::from(delta);
+                let newsize = newsize.clamp(i64::MIN.into(), 0) as i64;
+                invalid(ConfigProblem::SizeNotPositive(newsize))
+            })?;
+        self.resize_info(
+            &info,
+            newsize as libc::off_t,
+            force,
+            FileGrowth::default(),
+        )?;
+        Ok(newsize)
+    }
+
+    /// Implements [`Md::resize_with_file`], given the device's current configuration.
+    fn resize_info(
+        &self,
+        info: &MdInfo,
+        newsize: libc::off_t,
+        force: bool,
+        growth: FileGrowth,
+    ) -> Result<(), MdError> {
+        let mut problems = Vec::new();
+        if newsize <= 0 {
+            problems.push(ConfigProblem::SizeNotPositive(newsize));
+        } else if newsize % libc::off_t::from(info.sectorsize) != 0 {
+            problems.push(ConfigProblem::SizeNotMultipleOfSectorsize {
+                size:       newsize as u64,
+                sectorsize: info.sectorsize,
+            });
+        } else if info.md_type == MdType::Swap
+            && newsize as u64 % page_size() != 0
+        {
+            // The kernel would fail with EDOM.
+            problems.push(ConfigProblem::SizeNotMultipleOfPageSize {
+                size:      newsize as u64,
+                page_size: page_size(),
+            });
         }
-        let devmd = fs::File::open("/dev/mdctl")?;
-        unsafe {
-            ioctl::mdiocresize(devmd.as_raw_fd(), &mut mdio)?;
+        if !problems.is_empty() {
+            return Err(MdError::invalid_config(
+                Some(self.unit),
+                info.file.as_deref(),
+                problems,
+            ));
         }
-        Ok(())
+        let newsize_u = newsize as u64;
+        if newsize_u < info.mediasize
+            && !force
+            && !info.options.contains(MdOptions::FORCE)
+        {
+            // The same error that the kernel would return.
+            let e = io::Error::from_raw_os_error(libc::EBUSY);
+            return Err(MdError::new(
+                Operation::Resize,
+                Some(self.unit),
+                None,
+                e,
+            ));
+        }
+        let file = match (&info.md_type, &info.file) {
+            (MdType::Vnode, Some(file)) => file,
+            _ => return self.resize(newsize, force),
+        };
+        let file_err = |e| {
+            MdError::new(Operation::ResizeFile, Some(self.unit), Some(file), e)
+        };
+        if newsize_u >= info.mediasize {
+            let oldlen =
+                vnode::grow(file, newsize_u, growth).map_err(file_err)?;
+            self.resize(newsize, force).inspect_err(|_| {
+                vnode::restore(file, oldlen);
+            })
+        } else {
+            self.resize(newsize, force)?;
+            vnode::shrink(file, newsize_u).map_err(file_err)
+        }
+    }
+
+    /// The device's sectorsize in bytes.
+    pub fn sectorsize(&self) -> u32 {
+        self.sectorsize
+    }
+
+    /// The device's size in bytes.
+    ///
+    /// This is recorded when the `Md` is created or obtained, and kept up to date by
+    /// [`Md::resize`] and related methods.  It won't reflect resizes done by other processes; use
+    /// [`Md::query`] for that.
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Use an already open [`MdControl`] for all further operations on this device, including
+    /// detaching on Drop.
+    ///
+    /// This is useful before entering a capability mode sandbox.
+    pub fn set_control(&mut self, ctl: Arc<MdControl>) {
+        self.ctl = Some(ctl);
+    }
+
+    /// Choose what to do with the device when this `Md` is dropped.
+    ///
+    /// See [`DropPolicy`].
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy;
     }
 
     /// Attempt to destroy the underlying device within the operating system.
     ///
     /// If unsuccessful, the device will not be changed.  If successful, the actual device will be
-    /// deallocated.  A common reason for failure is `EBUSY`, which indicates that some other
-    /// process has the device open.
-    pub fn try_destroy(mut self) -> std::result::Result<(), (Self, io::Error)> {
+    /// deallocated.  A common reason for failure is [`MdError::Busy`], which indicates that some
+    /// other process has the device open.
+    pub fn try_destroy(mut self) -> std::result::Result<(), (Self, MdError)> {
         match self.detach(false) {
             Ok(()) => {
-                std::mem::forget(self);
+                self.owned = false;
                 Ok(())
             }
             Err(e) => Err((self, e)),
         }
     }
 
+    /// Make the current process the device's owner.
+    ///
+    /// An `Md` records the process that created it.  If that process forks, the child's copy of
+    /// the `Md` will not detach the device on Drop.  Call this method in the child to make the
+    /// child responsible for the device instead.  The parent should then relinquish its own copy,
+    /// for example with [`DropPolicy::Leak`].
+    ///
+    /// If the child has enabled its own cleanup registry with [`enable_registry`], the device will
+    /// be registered there.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use mdconfig::{Builder, DropPolicy};
+    /// let mut md = Builder::malloc(1 << 20).create().unwrap();
+    /// match unsafe { libc::fork() } {
+    ///     0 => {
+    ///         // Child
+    ///         md.transfer_to_child();
+    ///     }
+    ///     _ => {
+    ///         // Parent
+    ///         md.set_drop_policy(DropPolicy::Leak);
+    ///     }
+    /// }
+    /// ```
+    pub fn transfer_to_child(&mut self) {
+        self.pid = std::process::id();
+        if self.owned {
+            self.registration = registry::register(self.unit);
+        }
+    }
+
     /// Report the device's unit number. e.g. the "0" in "md0".
     ///
     /// # Example
@@ -412,13 +1688,522 @@ impl Md {
     pub fn unit(&self) -> u32 {
         self.unit
     }
+
+    /// Wait until the device is ready for use.
+    ///
+    /// [`Builder::create`] returns as soon as the kernel has created the device.  But its device
+    /// node may not exist yet, and GEOM classes may still be tasting it.  While they are, a
+    /// non-forced detach or an exclusive open will fail with `EBUSY`.  This method blocks until
+    /// the device node exists and no GEOM consumer has the device open, or until `timeout`
+    /// expires, in which case it fails with [`MdError::TimedOut`].
+    ///
+    /// Consumers within this process count too, so don't call it while holding the device open.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// let md = mdconfig::Builder::swap(1 << 20).create().unwrap();
+    /// md.wait_ready(Duration::from_secs(5)).unwrap();
+    /// md.try_destroy().unwrap();
+    /// ```
+    pub fn wait_ready(&self, timeout: Duration) -> Result<(), MdError> {
+        let wait_err =
+            |e| MdError::new(Operation::Wait, Some(self.unit), None, e);
+        let start = Instant::now();
+        let mut delay = Duration::from_millis(1);
+        loop {
+            if self.path.exists() {
+                let mode = geom::provider_mode(&self.name).map_err(wait_err)?;
+                if mode.as_deref() == Some(geom::IDLE) {
+                    return Ok(());
+                }
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Err(wait_err(io::Error::from_raw_os_error(
+                    libc::ETIMEDOUT,
+                )));
+            }
+            thread::sleep(delay.min(timeout - elapsed));
+            delay = (delay * 2).min(Duration::from_millis(100));
+        }
+    }
 }
 
 impl Drop for Md {
     fn drop(&mut self) {
-        let r = self.detach(true);
-        if !std::thread::panicking() {
-            r.expect("Error during MDIOCDETACH during drop");
+        if self.pid != std::process::id() {
+            // This is a forked copy.  The device belongs to the parent.
+            return;
+        }
+        if self
+            .registration
+            .is_some_and(|slot| !registry::deregister(slot))
+        {
+            // Already detached by detach_registered.
+            return;
+        }
+        if !self.owned {
+            return;
+        }
+        let r = match self.drop_policy {
+            DropPolicy::Force => self.detach(true),
+            DropPolicy::TryThenForce(grace) => self.detach_retrying(
+                &DestroyOptions::new(grace).force_on_timeout(true),
+            ),
+            DropPolicy::TryOnly => self.detach(false),
+            DropPolicy::Leak => Ok(()),
+        };
+        let Err(e) = r else {
+            return;
+        };
+        // With TryOnly, leaving a busy device attached is the expected outcome.
+        let expected = self.drop_policy == DropPolicy::TryOnly
+            && matches!(e, MdError::Busy(_));
+        if let Some(hook) = &self.on_drop_error {
+            (hook.0)(&e);
+        } else if !expected && !thread::panicking() {
+            panic!("Error during MDIOCDETACH during drop: {e:?}");
+        }
+    }
+}
+
+/// The system's page size.
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+/// The kernel's maximum I/O size.  No sectorsize may exceed it.
+#[cfg(target_os = "freebsd")]
+fn maxphys() -> u64 {
+    let mut maxphys: libc::c_ulong = 0;
+    let mut len = std::mem::size_of_val(&maxphys);
+    let r = unsafe {
+        libc::sysctlbyname(
+            c"kern.maxphys".as_ptr(),
+            (&raw mut maxphys).cast(),
+            &mut len,
+            ptr::null(),
+            0,
+        )
+    };
+    if r == 0 {
+        maxphys as u64
+    } else {
+        // Before FreeBSD 13, it was a compile-time constant.
+        128 << 10
+    }
+}
+
+/// The kernel's maximum I/O size.  No sectorsize may exceed it.
+#[cfg(not(target_os = "freebsd"))]
+fn maxphys() -> u64 {
+    // FreeBSD's default
+    1 << 20
+}
+
+/// Round `bytes` up to a multiple of `granularity`, or return `None` on overflow.
+fn round_up(bytes: u64, granularity: u64) -> Option<u64> {
+    bytes.div_ceil(granularity).checked_mul(granularity)
+}
+
+/// A device's size must be a multiple of this.
+fn size_granularity(info: &MdInfo) -> u64 {
+    let sectorsize = u64::from(info.sectorsize.max(1));
+    if info.md_type != MdType::Swap {
+        return sectorsize;
+    }
+    // The least common multiple of the sectorsize and the page size
+    let page_size = page_size();
+    let (mut a, mut b) = (sectorsize, page_size);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    sectorsize / a * page_size
+}
+
+/// Parse a device path like "/dev/md7" into its unit number.
+fn unit_from_path(path: &Path) -> Result<u32, MdError> {
+    path.file_name()
+        .and_then(OsStr::to_str)
+        .and_then(|name| name.strip_prefix("md"))
+        .filter(|unit| unit.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|unit| unit.parse::<u32>().ok())
+        .ok_or_else(|| {
+            let e = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not an md device", path.display()),
+            );
+            MdError::new(Operation::Query, None, Some(path), e)
+        })
+}
+
+/// A handle to an existing md device that this process does not own.
+///
+/// It provides the same API as [`Md`], via `Deref`, but it will never detach the device on Drop.
+/// It's useful for working with devices created by
+/// [mdconfig(8)](https://man.freebsd.org/cgi/man.cgi?query=mdconfig), rc scripts, or other
+/// processes.
+///
+/// # Example
+/// ```no_run
+/// # use std::path::Path;
+/// let md = mdconfig::MdRef::from_path(Path::new("/dev/md7")).unwrap();
+/// md.resize(1 << 30, false).unwrap();
+/// // md7 remains after `md` is dropped.
+/// ```
+#[derive(Debug)]
+pub struct MdRef(Md);
+
+impl MdRef {
+    /// Obtain a handle to an existing md device, without taking ownership of it.
+    pub fn from_unit(unit: u32) -> Result<Self, MdError> {
+        let info = query(unit)?;
+        Ok(MdRef(Md::new(&info, false)))
+    }
+
+    /// Like [`MdRef::from_unit`], but takes the device's path, like "/dev/md7".
+    pub fn from_path(path: &Path) -> Result<Self, MdError> {
+        MdRef::from_unit(unit_from_path(path)?)
+    }
+
+    /// Attempt to destroy the underlying device within the operating system.
+    ///
+    /// See [`Md::try_destroy`].
+    pub fn try_destroy(mut self) -> std::result::Result<(), (Self, MdError)> {
+        match self.0.detach(false) {
+            Ok(()) => Ok(()),
+            Err(e) => Err((self, e)),
+        }
+    }
+}
+
+impl Deref for MdRef {
+    type Target = Md;
+
+    fn deref(&self) -> &Md {
+        &self.0
+    }
+}
+
+/// A memory disk preloaded by [loader(8)](https://man.freebsd.org/cgi/man.cgi?query=loader), such
+/// as an mfsroot.
+///
+/// Like [`MdRef`], it will never detach the device on Drop.  It provides the rest of [`Md`]'s API
+/// via `Deref`.
+///
+/// # Example
+/// ```no_run
+/// for md in mdconfig::preloaded().unwrap() {
+///     println!("{}: {} bytes at {:#x}", md.name(), md.size(), md.base());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct PreloadedMd {
+    md:   MdRef,
+    info: MdInfo,
+}
+
+impl PreloadedMd {
+    /// Obtain a handle to an existing preloaded md device.
+    ///
+    /// Fails with [`MdError::WrongType`] if the device exists but was not preloaded.
+    pub fn from_unit(unit: u32) -> Result<Self, MdError> {
+        let info = query(unit)?;
+        PreloadedMd::from_info(info).ok_or_else(|| {
+            MdError::wrong_type(
+                Operation::Query,
+                unit,
+                format!("md{unit} is not preloaded"),
+            )
+        })
+    }
+
+    fn from_info(info: MdInfo) -> Option<Self> {
+        (info.md_type == MdType::Preload).then(|| PreloadedMd {
+            md: MdRef(Md::new(&info, false)),
+            info,
+        })
+    }
+
+    /// The base address of the preloaded image, as reported in `md_base`.
+    pub fn base(&self) -> u64 {
+        self.info.base
+    }
+
+    /// The device's configuration, as reported by the kernel when this handle was obtained.
+    pub fn info(&self) -> &MdInfo {
+        &self.info
+    }
+
+    /// Size of the preloaded image, in bytes.
+    pub fn size(&self) -> u64 {
+        self.info.mediasize
+    }
+}
+
+impl Deref for PreloadedMd {
+    type Target = MdRef;
+
+    fn deref(&self) -> &MdRef {
+        &self.md
+    }
+}
+
+/// List every preloaded md device on the system.
+///
+/// See [`PreloadedMd`].
+pub fn preloaded() -> Result<Vec<PreloadedMd>, MdError> {
+    Ok(list_info()?
+        .into_iter()
+        .filter_map(PreloadedMd::from_info)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    mod options {
+        use super::*;
+
+        #[test]
+        fn display() {
+            assert_eq!(MdOptions::empty().to_string(), "");
+            assert_eq!(
+                (MdOptions::ASYNC | MdOptions::MUSTDEALLOC).to_string(),
+                "async,mustdealloc"
+            );
+        }
+
+        #[test]
+        fn from_str() {
+            assert_eq!("".parse::<MdOptions>().unwrap(), MdOptions::empty());
+            assert_eq!(
+                "readonly, cache".parse::<MdOptions>().unwrap(),
+                MdOptions::READONLY | MdOptions::CACHE
+            );
+            assert!("autounit".parse::<MdOptions>().is_err());
+            assert!("bogus".parse::<MdOptions>().is_err());
+            assert!("0xfoo".parse::<MdOptions>().is_err());
+        }
+
+        /// mdconfig's own spellings, as given to repeated `-o` flags
+        #[test]
+        fn from_str_mdconfig() {
+            assert_eq!("ro".parse::<MdOptions>().unwrap(), MdOptions::READONLY);
+            assert_eq!(
+                "cluster,noreadonly".parse::<MdOptions>().unwrap(),
+                MdOptions::CLUSTER
+            );
+            assert_eq!(
+                "ro,async,noro".parse::<MdOptions>().unwrap(),
+                MdOptions::ASYNC
+            );
+            assert!("no".parse::<MdOptions>().is_err());
+            assert!("nobogus".parse::<MdOptions>().is_err());
+        }
+
+        #[test]
+        fn md_type() {
+            for t in [
+                MdType::Malloc,
+                MdType::Preload,
+                MdType::Vnode,
+                MdType::Swap,
+                MdType::Null,
+            ] {
+                assert_eq!(t, t.to_string().parse::<MdType>().unwrap());
+            }
+        }
+
+        /// Bits without a name survive a round trip
+        #[test]
+        fn unknown_bits() {
+            let options =
+                MdOptions::CACHE | MdOptions::from_bits_retain(1 << 30);
+            let s = options.to_string();
+            assert_eq!(s, "cache,0x40000000");
+            assert_eq!(s.parse::<MdOptions>().unwrap(), options);
+        }
+    }
+
+    mod validate {
+        use super::*;
+
+        fn problems(builder: Builder) -> Vec<ConfigProblem> {
+            match builder.validate() {
+                Ok(()) => Vec::new(),
+                Err(MdError::InvalidConfig(ctx, problems)) => {
+                    assert_eq!(ctx.operation(), Operation::Validate);
+                    problems
+                }
+                Err(e) => panic!("Unexpected error {e}"),
+            }
+        }
+
+        #[test]
+        fn create_validates() {
+            // Validation happens before touching the kernel, so this works anywhere.
+            let e = Builder::swap(1 << 20).cache(true).create().unwrap_err();
+            assert!(matches!(e, MdError::InvalidConfig(..)));
+        }
+
+        #[test]
+        #[allow(clippy::reversed_empty_ranges)]
+        fn empty_unit_range() {
+            assert_eq!(
+                problems(Builder::swap(1 << 20).unit_range(5..5)),
+                vec![ConfigProblem::EmptyUnitRange]
+            );
+            assert_eq!(
+                problems(Builder::swap(1 << 20).unit_range(5..0)),
+                vec![ConfigProblem::EmptyUnitRange]
+            );
+            assert!(
+                problems(Builder::swap(1 << 20).unit_range(0..5).unit(9))
+                    .is_empty()
+            );
+        }
+
+        #[test]
+        fn incomplete_geometry() {
+            assert_eq!(
+                problems(Builder::swap(1 << 20).heads_per_cylinder(8)),
+                vec![ConfigProblem::IncompleteGeometry]
+            );
+            assert_eq!(
+                problems(Builder::swap(1 << 20).sectors_per_track(8)),
+                vec![ConfigProblem::IncompleteGeometry]
+            );
+        }
+
+        #[test]
+        fn lists_every_problem() {
+            let builder = Builder::malloc(1000)
+                .sectorsize(4096)
+                .sectors_per_track(63)
+                .verify(true);
+            assert_eq!(
+                problems(builder),
+                vec![
+                    ConfigProblem::SizeNotMultipleOfSectorsize {
+                        size:       1000,
+                        sectorsize: 4096,
+                    },
+                    ConfigProblem::IncompleteGeometry,
+                    ConfigProblem::VnodeOnlyOptions(MdOptions::VERIFY),
+                ]
+            );
+        }
+
+        #[test]
+        fn ok() {
+            assert!(problems(Builder::malloc(1 << 20)).is_empty());
+            assert!(
+                problems(Builder::null(1 << 20).sectorsize(4096)).is_empty()
+            );
+            let builder = Builder::vnode(Path::new("/tmp/foo.img"))
+                .async_(true)
+                .cache(true)
+                .mustdealloc(true)
+                .verify(true);
+            assert!(problems(builder).is_empty());
+        }
+
+        #[test]
+        fn label_too_long() {
+            let label = "x".repeat(libc::PATH_MAX as usize);
+            assert_eq!(
+                problems(Builder::null(1 << 20).label(&label)),
+                vec![ConfigProblem::LabelTooLong]
+            );
+        }
+
+        #[test]
+        fn sectorsize_too_large() {
+            let sectorsize = maxphys() as u32 * 2;
+            assert_eq!(
+                problems(
+                    Builder::malloc(u64::from(sectorsize) * 2)
+                        .sectorsize(sectorsize)
+                ),
+                vec![ConfigProblem::SectorsizeTooLarge {
+                    sectorsize,
+                    maxphys: maxphys(),
+                }]
+            );
+        }
+
+        #[test]
+        fn sectorsize_not_power_of_two() {
+            assert_eq!(
+                problems(Builder::malloc(3000 * 10).sectorsize(3000)),
+                vec![ConfigProblem::SectorsizeNotPowerOfTwo(3000)]
+            );
+        }
+
+        /// The kernel would reject these with EDOM
+        #[test]
+        fn size_not_multiple_of_page_size() {
+            assert_eq!(
+                problems(Builder::swap(512)),
+                vec![ConfigProblem::SizeNotMultipleOfPageSize {
+                    size:      512,
+                    page_size: page_size(),
+                }]
+            );
+            assert!(problems(Builder::malloc(512)).is_empty());
+            assert!(problems(Builder::swap(page_size() * 3)).is_empty());
+        }
+
+        #[test]
+        fn size_not_positive() {
+            assert_eq!(
+                problems(Builder::swap(0)),
+                vec![ConfigProblem::SizeNotPositive(0)]
+            );
+            assert_eq!(
+                problems(Builder::vnode(Path::new("/tmp/foo.img")).size(-512)),
+                vec![ConfigProblem::SizeNotPositive(-512)]
+            );
+        }
+
+        #[test]
+        fn size_str_invalid() {
+            assert_eq!(
+                problems(Builder::swap(0).size_str("lots")),
+                vec![ConfigProblem::InvalidSize("lots".into())]
+            );
+        }
+
+        /// Sectors are resolved against the sectorsize, no matter the order in which they're set.
+        #[test]
+        fn size_str_sectorsize() {
+            assert!(
+                problems(Builder::malloc(0).size_str("3s").sectorsize(4096))
+                    .is_empty()
+            );
+            assert!(
+                problems(Builder::malloc(0).sectorsize(4096).size_str("3s"))
+                    .is_empty()
+            );
+            assert!(
+                !problems(Builder::malloc(0).sectorsize(4096).size_str("3k"))
+                    .is_empty()
+            );
+        }
+
+        #[test]
+        fn vnode_only_options() {
+            for builder in [
+                Builder::malloc(1 << 20).async_(true),
+                Builder::swap(1 << 20).cache(true),
+                Builder::null(1 << 20).mustdealloc(true),
+            ] {
+                let p = problems(builder);
+                assert!(matches!(p[..], [ConfigProblem::VnodeOnlyOptions(_)]));
+            }
         }
     }
 }
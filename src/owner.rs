@@ -0,0 +1,232 @@
+//! Ownership tags, for reaping devices leaked by processes that died without dropping them
+use std::{fmt, io, str::FromStr};
+
+use super::{MdControl, MdError, MdInfo};
+
+/// The word that introduces an ownership tag within a label.
+const PREFIX: &str = "mdconfig-owner=";
+
+/// Identifies the process that created an md device.
+///
+/// Set by [`Builder::owner_tag`](crate::Builder::owner_tag), which appends it to the device's
+/// label, and used by [`reap_orphans`] to find devices whose creator no longer exists.  It records
+/// the creator's pid, its session id, and, where the operating system can report it, its start
+/// time.  The latter two guard against pid reuse.
+///
+/// In a label, it's formatted as a single word like `mdconfig-owner=1234,1200,1700000000123456`,
+/// where the fields are the pid, session id, and start time in microseconds since the epoch, or
+/// `-` if the start time is unknown.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct OwnerTag {
+    pid:   i32,
+    sid:   i32,
+    start: Option<u64>,
+}
+
+impl OwnerTag {
+    /// The tag for the current process.
+    pub fn current() -> Self {
+        let pid = std::process::id() as i32;
+        OwnerTag {
+            pid,
+            sid: unsafe { libc::getsid(0) },
+            start: start_time(pid),
+        }
+    }
+
+    /// Find an ownership tag within a device's label.
+    pub fn from_label(label: &str) -> Option<Self> {
+        label
+            .split_ascii_whitespace()
+            .rev()
+            .find(|word| word.starts_with(PREFIX))
+            .and_then(|word| word.parse().ok())
+    }
+
+    /// Does the process that created the device still exist?
+    ///
+    /// A process with the same pid but a different start time is presumed to be an unrelated
+    /// process that reused the pid.  The session id is only compared when either start time is
+    /// unknown, because a live owner may legitimately change its session with `setsid`.
+    pub fn is_alive(&self) -> bool {
+        if unsafe { libc::kill(self.pid, 0) } != 0
+            && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+        {
+            return false;
+        }
+        if let (Some(start), Some(now)) = (self.start, start_time(self.pid)) {
+            return start == now;
+        }
+        let sid = unsafe { libc::getsid(self.pid) };
+        sid == -1 || sid == self.sid
+    }
+
+    /// The creating process's pid.
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// The creating process's session id.
+    pub fn sid(&self) -> i32 {
+        self.sid
+    }
+
+    /// The creating process's start time in microseconds since the epoch, if known.
+    pub fn start_time(&self) -> Option<u64> {
+        self.start
+    }
+}
+
+impl fmt::Display for OwnerTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{PREFIX}{},{},", self.pid, self.sid)?;
+        match self.start {
+            Some(start) => write!(f, "{start}"),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+impl FromStr for OwnerTag {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid owner tag {s:?}"),
+            )
+        };
+        let fields = s.strip_prefix(PREFIX).ok_or_else(invalid)?;
+        let mut fields = fields.split(',');
+        let mut next = || fields.next().ok_or_else(invalid);
+        let pid = next()?.parse().map_err(|_| invalid())?;
+        let sid = next()?.parse().map_err(|_| invalid())?;
+        let start = match next()? {
+            "-" => None,
+            start => Some(start.parse().map_err(|_| invalid())?),
+        };
+        if fields.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(OwnerTag { pid, sid, start })
+    }
+}
+
+/// Look up a process's start time, in microseconds since the epoch.
+#[cfg(target_os = "freebsd")]
+fn start_time(pid: i32) -> Option<u64> {
+    let mut kp = std::mem::MaybeUninit::<libc::kinfo_proc>::zeroed();
+    let mut len = std::mem::size_of::<libc::kinfo_proc>();
+    let mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_PID, pid];
+    let r = unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            kp.as_mut_ptr().cast(),
+            &mut len,
+            std::ptr::null(),
+            0,
+        )
+    };
+    if r != 0 || len < std::mem::size_of::<libc::kinfo_proc>() {
+        return None;
+    }
+    let start = unsafe { kp.assume_init() }.ki_start;
+    Some(start.tv_sec as u64 * 1_000_000 + start.tv_usec as u64)
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn start_time(_pid: i32) -> Option<u64> {
+    None
+}
+
+/// Forcefully detach every md device whose creating process no longer exists.
+///
+/// Only devices created with [`Builder::owner_tag`](crate::Builder::owner_tag) are considered.
+/// This is useful as a cleanup step before running tests, in case a previous run was killed before
+/// its devices could be dropped.  Returns the devices that were detached.
+///
+/// # Example
+/// ```no_run
+/// for info in mdconfig::reap_orphans().unwrap() {
+///     println!("Detached orphaned md{}", info.unit);
+/// }
+/// ```
+pub fn reap_orphans() -> Result<Vec<MdInfo>, MdError> {
+    let ctl = MdControl::open()?;
+    let mut reaped = Vec::new();
+    // Leaked devices may be too numerous for MDIOCLIST alone.
+    for unit in ctl.list_all()? {
+        let info = match ctl.query(unit) {
+            Ok(info) => info,
+            Err(MdError::NoSuchUnit(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        if info.owner().is_none_or(|owner| owner.is_alive()) {
+            continue;
+        }
+        match ctl.detach(unit, true) {
+            Ok(()) => reaped.push(info),
+            // Somebody else detached it first.
+            Err(MdError::NoSuchUnit(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(reaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    /// Return the pid of a process that has already exited
+    fn dead_pid() -> i32 {
+        let mut child = Command::new("true").spawn().unwrap();
+        let pid = child.id() as i32;
+        child.wait().unwrap();
+        pid
+    }
+
+    #[test]
+    fn current_is_alive() {
+        assert!(OwnerTag::current().is_alive());
+    }
+
+    #[test]
+    fn dead() {
+        let tag = format!("mdconfig-owner={},1,-", dead_pid());
+        assert!(!tag.parse::<OwnerTag>().unwrap().is_alive());
+    }
+
+    #[test]
+    fn from_label() {
+        let tag = OwnerTag::from_label("foo bar mdconfig-owner=12,10,123456")
+            .unwrap();
+        assert_eq!(tag.pid(), 12);
+        assert_eq!(tag.sid(), 10);
+        assert_eq!(tag.start_time(), Some(123456));
+        assert_eq!(OwnerTag::from_label("foo bar"), None);
+        assert_eq!(OwnerTag::from_label("mdconfig-owner=12"), None);
+    }
+
+    /// A live owner whose session id changed is still alive, if its start time is known.
+    #[cfg(target_os = "freebsd")]
+    #[test]
+    fn new_session() {
+        let cur = OwnerTag::current();
+        let tag = OwnerTag {
+            sid: cur.sid + 1,
+            ..cur
+        };
+        assert!(tag.is_alive());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let tag = OwnerTag::current();
+        assert_eq!(tag.to_string().parse::<OwnerTag>().unwrap(), tag);
+    }
+}
@@ -0,0 +1,172 @@
+//! A process-wide registry of live devices, for cleanup when Drop can't run
+use std::{
+    ptr,
+    sync::{
+        OnceLock,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use super::{MdControl, MdError};
+
+/// Maximum number of devices that can be registered at once.
+const SLOTS: usize = 1024;
+/// Marks an unused slot.
+const EMPTY: u32 = u32::MAX;
+/// Marks a slot whose device was detached by [`detach_registered`], but whose [`Md`](crate::Md)
+/// still exists.  The slot can't be reused until the `Md` is dropped, so that it can tell that its
+/// device is gone, even if a new device has since been given the same unit number.
+const DETACHED: u32 = u32::MAX - 1;
+/// Signals that trigger cleanup, once [`install_signal_handlers`] is called.
+const SIGNALS: [libc::c_int; 5] = [
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTERM,
+    libc::SIGABRT,
+];
+
+/// Unit numbers of registered devices.  A fixed array of atomics, so it's async-signal-safe.
+static UNITS: [AtomicU32; SLOTS] = [const { AtomicU32::new(EMPTY) }; SLOTS];
+/// Opened in advance, because opening files is not async-signal-safe.  Set iff enabled.
+static CTL: OnceLock<MdControl> = OnceLock::new();
+/// The process that enabled the registry.  A forked child inherits a copy of the registry, but
+/// must not act on it.
+static PID: AtomicU32 = AtomicU32::new(0);
+/// Signal dispositions in effect before [`install_signal_handlers`].
+static PREVIOUS: OnceLock<[libc::sigaction; SIGNALS.len()]> = OnceLock::new();
+
+/// Enable the cleanup registry.
+///
+/// Once enabled, every device subsequently created by [`Builder::create`](crate::Builder::create)
+/// or [`MdControl::create`] is recorded until its [`Md`](crate::Md) is dropped, destroyed, or made
+/// persistent.  Any devices still registered when the process calls
+/// [`std::process::exit`] will be forcefully detached.  To also clean up on signals, use
+/// [`install_signal_handlers`].
+///
+/// At most 1024 devices can be registered at once.  Beyond that, devices are created normally but
+/// not registered.  A device detached by [`detach_registered`] still counts until its
+/// [`Md`](crate::Md) is dropped.
+///
+/// A forked child's copy of the registry is inert.  The child may call this function to start a
+/// fresh registry of its own.
+pub fn enable_registry() -> Result<(), MdError> {
+    if CTL.get().is_none() {
+        let ctl = MdControl::open()?;
+        if CTL.set(ctl).is_ok() {
+            unsafe { libc::atexit(at_exit) };
+        }
+    }
+    let pid = std::process::id();
+    if PID.swap(pid, Ordering::AcqRel) != pid {
+        // Forget any devices inherited from the parent.
+        for slot in UNITS.iter() {
+            slot.store(EMPTY, Ordering::Release);
+        }
+    }
+    Ok(())
+}
+
+/// Is the registry enabled in this process?
+fn enabled() -> Option<&'static MdControl> {
+    CTL.get()
+        .filter(|_| PID.load(Ordering::Acquire) == std::process::id())
+}
+
+/// Enable the cleanup registry, and install handlers that forcefully detach every registered
+/// device upon `SIGHUP`, `SIGINT`, `SIGQUIT`, `SIGTERM`, or `SIGABRT`.
+///
+/// After cleaning up, each handler restores the signal's previous disposition and re-raises it.
+/// Signals that were being ignored are left alone.  `SIGABRT` is included because that's how a
+/// panic terminates the process with `panic = "abort"`.
+///
+/// # Example
+/// ```no_run
+/// mdconfig::install_signal_handlers().unwrap();
+/// let md = mdconfig::Builder::malloc(1 << 20).create().unwrap();
+/// // If the process is interrupted by Ctrl-C, md will be detached anyway.
+/// ```
+pub fn install_signal_handlers() -> Result<(), MdError> {
+    enable_registry()?;
+    let mut previous =
+        [unsafe { std::mem::zeroed::<libc::sigaction>() }; SIGNALS.len()];
+    for (sig, old) in SIGNALS.iter().zip(previous.iter_mut()) {
+        unsafe { libc::sigaction(*sig, ptr::null(), old) };
+    }
+    if PREVIOUS.set(previous).is_err() {
+        // Already installed.
+        return Ok(());
+    }
+    for (sig, old) in SIGNALS.iter().zip(previous.iter()) {
+        if old.sa_sigaction == libc::SIG_IGN {
+            continue;
+        }
+        let mut sa = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        sa.sa_sigaction = handler as extern "C" fn(libc::c_int) as usize;
+        unsafe {
+            libc::sigemptyset(&mut sa.sa_mask);
+            libc::sigaction(*sig, &sa, ptr::null_mut());
+        }
+    }
+    Ok(())
+}
+
+/// Forcefully detach every registered device, and clear the registry.
+///
+/// This is async-signal-safe, so it may be called from a custom signal handler.  Any
+/// [`Md`](crate::Md) whose device is detached this way will not try to detach it again on Drop,
+/// even if its unit number has since been reused by a new device.
+pub fn detach_registered() {
+    let Some(ctl) = enabled() else {
+        return;
+    };
+    for slot in UNITS.iter() {
+        let unit = slot.load(Ordering::Acquire);
+        if unit == EMPTY || unit == DETACHED {
+            continue;
+        }
+        // If this fails, then the Md was dropped concurrently, and it will detach the device.
+        if slot
+            .compare_exchange(
+                unit,
+                DETACHED,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            // Nothing useful can be done with an error here.
+            let _ = ctl.detach(unit, true);
+        }
+    }
+}
+
+/// Record a newly created device, if the registry is enabled.  Returns the slot it was recorded
+/// in.
+pub(crate) fn register(unit: u32) -> Option<usize> {
+    enabled()?;
+    UNITS.iter().position(|slot| {
+        slot.compare_exchange(EMPTY, unit, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    })
+}
+
+/// Release a slot returned by [`register`].  Returns false if [`detach_registered`] has already
+/// detached its device.
+pub(crate) fn deregister(slot: usize) -> bool {
+    UNITS[slot].swap(EMPTY, Ordering::AcqRel) != DETACHED
+}
+
+extern "C" fn at_exit() {
+    detach_registered();
+}
+
+extern "C" fn handler(sig: libc::c_int) {
+    detach_registered();
+    if let Some(previous) = PREVIOUS.get() {
+        let i = SIGNALS.iter().position(|s| *s == sig).unwrap_or_default();
+        unsafe { libc::sigaction(sig, &previous[i], ptr::null_mut()) };
+    }
+    // The signal is blocked until this handler returns.  Then the previous disposition applies.
+    unsafe { libc::raise(sig) };
+}
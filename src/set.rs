@@ -0,0 +1,118 @@
+//! Groups of md devices that are created and destroyed together
+use std::{ops::Index, path::Path, slice};
+
+use super::{Builder, Md, MdControl, MdError};
+
+/// A group of md devices, created together and owned as one.
+///
+/// Useful for tests that need several devices at once, such as the members of a mirror.  If any
+/// device can't be created, then those that already were are immediately detached.  On Drop, the
+/// devices are dropped in reverse order of creation.
+///
+/// # Example
+/// ```no_run
+/// # use mdconfig::{Builder, MdSet};
+/// let set = MdSet::create((0..3).map(|_| Builder::swap(1 << 30))).unwrap();
+/// let mut cmd = std::process::Command::new("zpool");
+/// cmd.args(["create", "testpool", "raidz"]).args(set.paths());
+/// ```
+#[derive(Debug)]
+pub struct MdSet {
+    mds: Vec<Md>,
+}
+
+impl MdSet {
+    /// Create a device from each `Builder`, in order.
+    ///
+    /// Every `Builder` is validated before any device is created.  If creating any device fails,
+    /// all previously created devices are forcefully detached, regardless of their
+    /// [`DropPolicy`](crate::DropPolicy), and the error is returned.
+    pub fn create<I>(builders: I) -> Result<Self, MdError>
+    where
+        I: IntoIterator<Item = Builder>,
+    {
+        let builders = builders.into_iter().collect::<Vec<_>>();
+        for builder in builders.iter() {
+            builder.validate()?;
+        }
+        let ctl = MdControl::open()?;
+        let mut mds = Vec::with_capacity(builders.len());
+        for builder in builders {
+            match builder.attach(&ctl, None) {
+                Ok(md) => mds.push(md),
+                Err(e) => {
+                    for mut md in mds.into_iter().rev() {
+                        // Best effort.  The original error is more interesting.
+                        let _ = md.detach(true);
+                        md.owned = false;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(MdSet { mds })
+    }
+
+    /// Convert into the individual devices, in order of creation.
+    pub fn into_vec(mut self) -> Vec<Md> {
+        std::mem::take(&mut self.mds)
+    }
+
+    /// Is the set empty?
+    pub fn is_empty(&self) -> bool {
+        self.mds.is_empty()
+    }
+
+    /// Iterate over the devices, in order of creation.
+    pub fn iter(&self) -> slice::Iter<'_, Md> {
+        self.mds.iter()
+    }
+
+    /// The number of devices in the set.
+    pub fn len(&self) -> usize {
+        self.mds.len()
+    }
+
+    /// Report the paths to the devices, like "/dev/md0", in order of creation.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.mds.iter().map(Md::path)
+    }
+
+    /// Attempt to destroy every device, in reverse order of creation, with [`Md::try_destroy`].
+    ///
+    /// Stops at the first failure, returning the devices that remain along with the error.
+    pub fn try_destroy(mut self) -> std::result::Result<(), (Self, MdError)> {
+        while let Some(md) = self.mds.pop() {
+            if let Err((md, e)) = md.try_destroy() {
+                self.mds.push(md);
+                return Err((self, e));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MdSet {
+    fn drop(&mut self) {
+        while let Some(md) = self.mds.pop() {
+            drop(md);
+        }
+    }
+}
+
+impl Index<usize> for MdSet {
+    type Output = Md;
+
+    fn index(&self, index: usize) -> &Md {
+        &self.mds[index]
+    }
+}
+
+impl<'a> IntoIterator for &'a MdSet {
+    type IntoIter = slice::Iter<'a, Md>;
+    type Item = &'a Md;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
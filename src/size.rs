@@ -0,0 +1,173 @@
+//! Human-readable sizes, compatible with mdconfig(8)
+use std::{fmt, io, str::FromStr};
+
+/// The size of an md device, as accepted by `mdconfig -s`.
+///
+/// `FromStr` accepts a number followed by an optional suffix, case-insensitively.  The suffixes
+/// `b`, `k`, `m`, `g`, and `t` denote bytes, kilobytes, megabytes, gigabytes, and terabytes,
+/// respectively.  A number with no suffix, or with an `s` suffix, is a count of sectors.  The
+/// number may be hexadecimal, with a `0x` prefix.  Then a trailing `b` is always the suffix, so
+/// `0x1b` is one byte, not 27 sectors.
+///
+/// `Display` prints byte sizes the same way as `mdconfig -l`, with at most four digits, rounding
+/// if necessary.
+///
+/// # Example
+/// ```
+/// # use mdconfig::Size;
+/// assert_eq!("512m".parse::<Size>().unwrap(), Size::Bytes(512 << 20));
+/// assert_eq!("2048s".parse::<Size>().unwrap(), Size::Sectors(2048));
+/// assert_eq!(Size::Bytes(1 << 20).to_string(), "1024K");
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Size {
+    /// A number of bytes.
+    Bytes(u64),
+    /// A number of sectors, whose size depends on the device.
+    Sectors(u64),
+}
+
+impl Size {
+    /// Convert to a number of bytes, given the device's sectorsize.
+    ///
+    /// Returns `None` on overflow.
+    pub fn bytes(self, sectorsize: u32) -> Option<u64> {
+        match self {
+            Size::Bytes(bytes) => Some(bytes),
+            Size::Sectors(sectors) => sectors.checked_mul(sectorsize.into()),
+        }
+    }
+}
+
+impl From<u64> for Size {
+    fn from(bytes: u64) -> Self {
+        Size::Bytes(bytes)
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const SUFFIXES: [&str; 7] = ["B", "K", "M", "G", "T", "P", "E"];
+        const MAX: u64 = 10_000;
+
+        let bytes = match self {
+            Size::Bytes(bytes) => *bytes,
+            Size::Sectors(sectors) => return write!(f, "{sectors}s"),
+        };
+        // Like humanize_number(3) with HN_AUTOSCALE, as used by mdconfig.
+        let mut quotient = bytes;
+        let mut remainder = 0;
+        let mut scale = 0;
+        while (quotient >= MAX || (quotient == MAX - 1 && remainder >= 512))
+            && scale < SUFFIXES.len() - 1
+        {
+            remainder = quotient % 1024;
+            quotient /= 1024;
+            scale += 1;
+        }
+        let rounded = quotient + (remainder + 512) / 1024;
+        write!(f, "{rounded}{}", SUFFIXES[scale])
+    }
+}
+
+impl FromStr for Size {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid size {s:?}"),
+            )
+        };
+        let s = s.trim();
+        let (number, suffix, radix) = match s.strip_prefix("0x") {
+            Some(hex) => {
+                // "b" is also a hex digit, so a suffix can only be the last character.
+                let split = if hex.ends_with(|c: char| {
+                    "bkmgts".contains(c.to_ascii_lowercase())
+                }) {
+                    hex.len() - 1
+                } else {
+                    hex.len()
+                };
+                let (number, suffix) = hex.split_at(split);
+                (number, suffix, 16)
+            }
+            None => {
+                let split =
+                    s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+                let (number, suffix) = s.split_at(split);
+                (number, suffix, 10)
+            }
+        };
+        // from_str_radix would also accept a sign.
+        if !number.chars().all(|c| c.is_digit(radix)) {
+            return Err(invalid());
+        }
+        let number =
+            u64::from_str_radix(number, radix).map_err(|_| invalid())?;
+        let shift = match suffix.to_ascii_lowercase().as_str() {
+            "" | "s" => return Ok(Size::Sectors(number)),
+            "b" => 0,
+            "k" => 10,
+            "m" => 20,
+            "g" => 30,
+            "t" => 40,
+            _ => return Err(invalid()),
+        };
+        number
+            .checked_mul(1 << shift)
+            .map(Size::Bytes)
+            .ok_or_else(invalid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(Size::Bytes(0).to_string(), "0B");
+        assert_eq!(Size::Bytes(512).to_string(), "512B");
+        assert_eq!(Size::Bytes(9999).to_string(), "9999B");
+        assert_eq!(Size::Bytes(10240).to_string(), "10K");
+        assert_eq!(Size::Bytes(1 << 21).to_string(), "2048K");
+        assert_eq!(Size::Bytes(10 << 20).to_string(), "10M");
+        assert_eq!(Size::Bytes(4 << 30).to_string(), "4096M");
+        assert_eq!(Size::Bytes(1 << 40).to_string(), "1024G");
+        assert_eq!(Size::Sectors(2048).to_string(), "2048s");
+    }
+
+    #[test]
+    fn from_str() {
+        assert_eq!("1024".parse::<Size>().unwrap(), Size::Sectors(1024));
+        assert_eq!("2048s".parse::<Size>().unwrap(), Size::Sectors(2048));
+        assert_eq!("4096b".parse::<Size>().unwrap(), Size::Bytes(4096));
+        assert_eq!("1k".parse::<Size>().unwrap(), Size::Bytes(1024));
+        assert_eq!("512m".parse::<Size>().unwrap(), Size::Bytes(512 << 20));
+        assert_eq!("4G".parse::<Size>().unwrap(), Size::Bytes(4 << 30));
+        assert_eq!("2t".parse::<Size>().unwrap(), Size::Bytes(2 << 40));
+        assert_eq!("0x10m".parse::<Size>().unwrap(), Size::Bytes(16 << 20));
+        assert_eq!("0x10b".parse::<Size>().unwrap(), Size::Bytes(16));
+        assert_eq!("0x1b".parse::<Size>().unwrap(), Size::Bytes(1));
+        assert_eq!("0x1B".parse::<Size>().unwrap(), Size::Bytes(1));
+        assert_eq!("0x1f".parse::<Size>().unwrap(), Size::Sectors(31));
+        assert!("0xb".parse::<Size>().is_err());
+        assert!("0x+1".parse::<Size>().is_err());
+        assert!("".parse::<Size>().is_err());
+        assert!("m".parse::<Size>().is_err());
+        assert!("12q".parse::<Size>().is_err());
+        assert!("1.5g".parse::<Size>().is_err());
+        assert!("99999999999t".parse::<Size>().is_err());
+    }
+
+    #[test]
+    fn sectors() {
+        assert_eq!(Size::Sectors(8).bytes(512), Some(4096));
+        assert_eq!(Size::Sectors(8).bytes(4096), Some(32768));
+        assert_eq!(Size::Bytes(100).bytes(4096), Some(100));
+        assert_eq!(Size::Sectors(u64::MAX).bytes(512), None);
+    }
+}
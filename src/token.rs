@@ -0,0 +1,249 @@
+//! Serializable tokens for md devices that outlive their creating process.
+use std::{
+    ffi::OsStr,
+    fmt,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use super::{MdError, MdInfo, MdOptions, Operation};
+
+/// A token describing an [`Md`](crate::Md) device that has been deliberately left attached.
+///
+/// Obtained from [`Md::into_persistent`](crate::Md::into_persistent), and redeemed with
+/// [`Md::reclaim`](crate::Md::reclaim).  It may be converted to and from a single line of text with
+/// `Display` and `FromStr`, so it can be stored in a file or passed between commands.
+///
+/// # Example
+/// ```no_run
+/// let md = mdconfig::Builder::swap(1 << 20).create().unwrap();
+/// let saved = md.into_persistent().unwrap().to_string();
+/// // Later, possibly in another process
+/// let token = saved.parse::<mdconfig::MdToken>().unwrap();
+/// let md = mdconfig::Md::reclaim(&token).unwrap();
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MdToken {
+    info: MdInfo,
+}
+
+impl MdToken {
+    pub(crate) fn new(info: MdInfo) -> Self {
+        MdToken { info }
+    }
+
+    /// The device's configuration at the time the token was created.
+    pub fn info(&self) -> &MdInfo {
+        &self.info
+    }
+
+    /// The label associated with the device, if any.
+    pub fn label(&self) -> Option<&str> {
+        self.info.label.as_deref()
+    }
+
+    /// Report the name of the device, like "md0".
+    pub fn name(&self) -> String {
+        format!("md{}", self.info.unit)
+    }
+
+    /// Report the device's unit number. e.g. the "0" in "md0".
+    pub fn unit(&self) -> u32 {
+        self.info.unit
+    }
+
+    /// Check that the device described by `info` is still the one that this token describes.
+    pub(crate) fn verify(&self, info: &MdInfo) -> Result<(), MdError> {
+        let mismatch = if info.md_type != self.info.md_type {
+            Some("type")
+        } else if info.mediasize != self.info.mediasize {
+            Some("mediasize")
+        } else if info.sectorsize != self.info.sectorsize {
+            Some("sectorsize")
+        } else if info.file != self.info.file {
+            Some("file")
+        } else if info.label != self.info.label {
+            Some("label")
+        } else {
+            None
+        };
+        match mismatch {
+            Some(field) => {
+                let e = io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} does not match its token: {field} differs",
+                        self.name()
+                    ),
+                );
+                Err(MdError::new(
+                    Operation::Reclaim,
+                    Some(self.unit()),
+                    None,
+                    e,
+                ))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for MdToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = &self.info;
+        write!(
+            f,
+            "{} type={} mediasize={} sectorsize={} options={} fwheads={} \
+             fwsectors={}",
+            self.name(),
+            info.md_type,
+            info.mediasize,
+            info.sectorsize,
+            info.options,
+            info.fwheads,
+            info.fwsectors
+        )?;
+        if let Some(label) = &info.label {
+            write!(f, " label={}", escape(label.as_bytes()))?;
+        }
+        if let Some(file) = &info.file {
+            write!(f, " file={}", escape(file.as_os_str().as_bytes()))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for MdToken {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid md token: {what}"),
+            )
+        };
+        let mut words = s.split_ascii_whitespace();
+        let unit = words
+            .next()
+            .and_then(|name| name.strip_prefix("md"))
+            .and_then(|unit| unit.parse::<u32>().ok())
+            .ok_or_else(|| invalid("missing device name"))?;
+        let mut md_type = None;
+        let mut mediasize = None;
+        let mut sectorsize = None;
+        let mut options = MdOptions::empty();
+        let mut fwheads = 0;
+        let mut fwsectors = 0;
+        let mut label = None;
+        let mut file = None;
+        for word in words {
+            let (key, value) =
+                word.split_once('=').ok_or_else(|| invalid(word))?;
+            let bad = || invalid(word);
+            match key {
+                "type" => md_type = Some(value.parse().map_err(|_| bad())?),
+                "mediasize" => {
+                    mediasize = Some(value.parse().map_err(|_| bad())?)
+                }
+                "sectorsize" => {
+                    sectorsize = Some(value.parse().map_err(|_| bad())?)
+                }
+                "options" => options = value.parse().map_err(|_| bad())?,
+                "fwheads" => fwheads = value.parse().map_err(|_| bad())?,
+                "fwsectors" => fwsectors = value.parse().map_err(|_| bad())?,
+                "label" => {
+                    let bytes = unescape(value).ok_or_else(bad)?;
+                    label = Some(String::from_utf8(bytes).map_err(|_| bad())?)
+                }
+                "file" => {
+                    let bytes = unescape(value).ok_or_else(bad)?;
+                    file = Some(PathBuf::from(OsStr::from_bytes(&bytes)))
+                }
+                _ => return Err(bad()),
+            }
+        }
+        let info = MdInfo {
+            unit,
+            md_type: md_type.ok_or_else(|| invalid("missing type"))?,
+            file,
+            mediasize: mediasize.ok_or_else(|| invalid("missing mediasize"))?,
+            sectorsize: sectorsize
+                .ok_or_else(|| invalid("missing sectorsize"))?,
+            options,
+            base: 0,
+            fwheads,
+            fwsectors,
+            label,
+        };
+        Ok(MdToken { info })
+    }
+}
+
+/// Percent-encode any bytes that would interfere with whitespace-separated parsing.
+fn escape(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+    for &b in bytes {
+        if b.is_ascii_graphic() && b != b'%' {
+            s.push(b as char);
+        } else {
+            s.push_str(&format!("%{b:02X}"));
+        }
+    }
+    s
+}
+
+fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::MdType;
+
+    /// Tokens can be converted to strings and back, without a kernel
+    #[test]
+    fn roundtrip() {
+        let s = "md42 type=vnode mediasize=2097152 sectorsize=512 \
+                 options=cluster,compress fwheads=0 fwsectors=0 \
+                 label=two%20words file=/tmp/100%25.img";
+        let token = s.parse::<MdToken>().unwrap();
+        assert_eq!(token.name(), "md42");
+        assert_eq!(token.label(), Some("two words"));
+        assert_eq!(token.info().md_type, MdType::Vnode);
+        assert_eq!(
+            token.info().file.as_deref(),
+            Some(Path::new("/tmp/100%.img"))
+        );
+        assert_eq!(token, token.to_string().parse::<MdToken>().unwrap());
+    }
+
+    /// Options unknown to this crate survive a round trip
+    #[test]
+    fn roundtrip_unknown_options() {
+        let s = "md3 type=swap mediasize=1048576 sectorsize=512 \
+                 options=reserve,0x40000000 fwheads=0 fwsectors=0";
+        let token = s.parse::<MdToken>().unwrap();
+        assert_eq!(
+            token.info().options,
+            MdOptions::RESERVE | MdOptions::from_bits_retain(1 << 30)
+        );
+        assert_eq!(token, token.to_string().parse::<MdToken>().unwrap());
+    }
+}
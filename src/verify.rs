@@ -0,0 +1,482 @@
+//! Deterministic data integrity checking
+use std::{fmt, ops::Range, os::unix::fs::FileExt};
+
+use super::{AlignedBuf, MdError, Operation};
+
+/// Identifies a sector written by a [`Verifier`].
+const MAGIC: &[u8; 8] = b"MDCFGVFY";
+/// Length of the header at the start of each sector: magic, seed, and LBA.
+const HEADER: usize = 24;
+/// Length of the trailer at the end of each sector: LBA and seed.
+const TRAILER: usize = 16;
+/// Maximum number of bytes transferred per I/O.
+const CHUNK: usize = 1 << 20;
+
+/// Writes a deterministic pattern to a range of sectors, and later checks that it's still there.
+///
+/// Every sector is stamped with its own logical block address and the verifier's seed, at both
+/// its start and its end, and filled with pseudorandom data derived from the two.  That makes
+/// each sector's contents unique, so a mismatch can usually be diagnosed from the data alone: see
+/// [`MismatchKind`].  The pattern is the same on every platform, so it may be checked by a
+/// different process, after re-attaching a vnode-backed device or after a crash.
+///
+/// It works with anything that supports positioned I/O, including an [`MdDisk`](crate::MdDisk)
+/// and a plain [`File`](std::fs::File).
+///
+/// # Example
+/// ```no_run
+/// # use std::fs::OpenOptions;
+/// use mdconfig::{Builder, Verifier};
+///
+/// let md = Builder::malloc(1 << 20).create().unwrap();
+/// let disk = md.open(OpenOptions::new().read(true).write(true)).unwrap();
+/// let verifier = Verifier::new(0xdead_beef, md.sectorsize());
+/// verifier.fill(&disk, 0..2048).unwrap();
+/// let report = verifier.verify(&disk, 0..2048).unwrap();
+/// assert!(report.is_ok(), "{report}");
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Verifier {
+    seed:           u64,
+    sectorsize:     usize,
+    max_mismatches: usize,
+}
+
+impl Verifier {
+    /// By default, [`Verifier::verify`] records the contents of at most this many mismatched
+    /// sectors.
+    pub const MAX_MISMATCHES: usize = 1024;
+
+    /// Create a verifier for sectors of `sectorsize` bytes, whose pattern is determined by `seed`.
+    ///
+    /// Use a different seed for each pass over the same sectors, so that stale data can be told
+    /// apart from fresh.
+    ///
+    /// # Panics
+    ///
+    /// If `sectorsize` is less than 64 bytes or is not a power of two.
+    pub fn new(seed: u64, sectorsize: u32) -> Self {
+        let sectorsize = sectorsize as usize;
+        assert!(
+            sectorsize >= 64 && sectorsize.is_power_of_two(),
+            "invalid sectorsize {sectorsize}"
+        );
+        Verifier {
+            seed,
+            sectorsize,
+            max_mismatches: Self::MAX_MISMATCHES,
+        }
+    }
+
+    /// The expected contents of the sector at `lba`.
+    pub fn expected(&self, lba: u64) -> Vec<u8> {
+        let mut buf = vec![0; self.sectorsize];
+        pattern(self.seed, lba, &mut buf);
+        buf
+    }
+
+    /// Write the pattern to every sector in `lbas`.
+    pub fn fill<F: FileExt>(
+        &self,
+        f: &F,
+        lbas: Range<u64>,
+    ) -> Result<(), MdError> {
+        let mut buf = AlignedBuf::new(self.chunk_len(), self.sectorsize);
+        let mut lba = lbas.start;
+        while lba < lbas.end {
+            let n = self.sectors_in_chunk(lba, &lbas);
+            let chunk = &mut buf[..n * self.sectorsize];
+            for (i, sector) in
+                chunk.chunks_exact_mut(self.sectorsize).enumerate()
+            {
+                pattern(self.seed, lba + i as u64, sector);
+            }
+            f.write_all_at(chunk, lba * self.sectorsize as u64)
+                .map_err(|e| {
+                    MdError::new(Operation::WriteDisk, None, None, e)
+                })?;
+            lba += n as u64;
+        }
+        Ok(())
+    }
+
+    /// Record the contents of at most this many mismatched sectors.  Any further mismatches are
+    /// counted, but not recorded.
+    pub fn max_mismatches(mut self, max_mismatches: usize) -> Self {
+        self.max_mismatches = max_mismatches;
+        self
+    }
+
+    /// The seed that determines the pattern.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Read back every sector in `lbas`, and report any that don't match the pattern.
+    ///
+    /// Only I/O errors are returned as `Err`.  Data that doesn't match is reported in the
+    /// [`VerifyReport`].
+    pub fn verify<F: FileExt>(
+        &self,
+        f: &F,
+        lbas: Range<u64>,
+    ) -> Result<VerifyReport, MdError> {
+        let mut buf = AlignedBuf::new(self.chunk_len(), self.sectorsize);
+        let mut expected = vec![0; self.sectorsize];
+        let mut report = VerifyReport {
+            checked:    0,
+            mismatched: 0,
+            mismatches: Vec::new(),
+        };
+        let mut lba = lbas.start;
+        while lba < lbas.end {
+            let n = self.sectors_in_chunk(lba, &lbas);
+            let chunk = &mut buf[..n * self.sectorsize];
+            f.read_exact_at(chunk, lba * self.sectorsize as u64)
+                .map_err(|e| {
+                    MdError::new(Operation::ReadDisk, None, None, e)
+                })?;
+            for (i, actual) in chunk.chunks_exact(self.sectorsize).enumerate() {
+                let lba = lba + i as u64;
+                report.checked += 1;
+                pattern(self.seed, lba, &mut expected);
+                if actual == &expected[..] {
+                    continue;
+                }
+                report.mismatched += 1;
+                if report.mismatches.len() < self.max_mismatches {
+                    report.mismatches.push(Mismatch {
+                        lba,
+                        kind: self.classify(lba, &expected, actual),
+                        expected: expected.clone(),
+                        actual: actual.to_vec(),
+                    });
+                }
+            }
+            lba += n as u64;
+        }
+        Ok(report)
+    }
+
+    /// Diagnose why `actual` doesn't match `expected`.
+    fn classify(
+        &self,
+        lba: u64,
+        expected: &[u8],
+        actual: &[u8],
+    ) -> MismatchKind {
+        if actual.iter().all(|b| *b == 0) {
+            return MismatchKind::Lost;
+        }
+        let tail = actual.len() - TRAILER;
+        match parse_header(actual) {
+            Some((seed, found)) if is_pattern(seed, found, actual) => {
+                if found != lba {
+                    MismatchKind::Misdirected { lba: found, seed }
+                } else {
+                    MismatchKind::Stale { seed }
+                }
+            }
+            Some((seed, found)) if seed == self.seed && found == lba => {
+                MismatchKind::Torn
+            }
+            _ if actual[tail..] == expected[tail..] => MismatchKind::Torn,
+            _ => MismatchKind::Corrupt,
+        }
+    }
+
+    fn chunk_len(&self) -> usize {
+        CHUNK.max(self.sectorsize) / self.sectorsize * self.sectorsize
+    }
+
+    /// How many sectors to transfer in one I/O, starting at `lba`.
+    fn sectors_in_chunk(&self, lba: u64, lbas: &Range<u64>) -> usize {
+        let max = (self.chunk_len() / self.sectorsize) as u64;
+        (lbas.end - lba).min(max) as usize
+    }
+}
+
+/// Fill `sector` with the pattern for `lba`.
+fn pattern(seed: u64, lba: u64, sector: &mut [u8]) {
+    let tail = sector.len() - TRAILER;
+    sector[..8].copy_from_slice(MAGIC);
+    sector[8..16].copy_from_slice(&seed.to_le_bytes());
+    sector[16..HEADER].copy_from_slice(&lba.to_le_bytes());
+    let mut state = splitmix64(seed ^ splitmix64(lba));
+    for word in sector[HEADER..tail].chunks_mut(8) {
+        let x = xorshift64star(&mut state).to_le_bytes();
+        word.copy_from_slice(&x[..word.len()]);
+    }
+    sector[tail..tail + 8].copy_from_slice(&lba.to_le_bytes());
+    sector[tail + 8..].copy_from_slice(&seed.to_le_bytes());
+}
+
+/// Is `sector` exactly the pattern for `lba` with `seed`?
+fn is_pattern(seed: u64, lba: u64, sector: &[u8]) -> bool {
+    let mut buf = vec![0; sector.len()];
+    pattern(seed, lba, &mut buf);
+    buf == sector
+}
+
+/// Extract the seed and LBA from a sector's header, if it has one.
+fn parse_header(sector: &[u8]) -> Option<(u64, u64)> {
+    if &sector[..8] != MAGIC {
+        return None;
+    }
+    let seed = u64::from_le_bytes(sector[8..16].try_into().unwrap());
+    let lba = u64::from_le_bytes(sector[16..HEADER].try_into().unwrap());
+    Some((seed, lba))
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn xorshift64star(state: &mut u64) -> u64 {
+    // The state must never be zero.
+    let mut x = if *state == 0 { 1 } else { *state };
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *state = x;
+    x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+}
+
+/// The outcome of [`Verifier::verify`].
+#[derive(Clone, Debug)]
+pub struct VerifyReport {
+    /// The number of sectors read back.
+    pub checked:    u64,
+    /// The number of sectors that didn't match, including any not recorded in `mismatches`.
+    pub mismatched: u64,
+    /// Details of the first mismatched sectors, in order of LBA.
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl VerifyReport {
+    /// Did every sector match?
+    pub fn is_ok(&self) -> bool {
+        self.mismatched == 0
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} sectors mismatched",
+            self.mismatched, self.checked
+        )?;
+        for m in self.mismatches.iter() {
+            write!(f, "\n  {m}")?;
+        }
+        if self.mismatched > self.mismatches.len() as u64 {
+            write!(
+                f,
+                "\n  ... and {} more",
+                self.mismatched - self.mismatches.len() as u64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A sector whose contents didn't match the pattern.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mismatch {
+    /// Logical block address of the sector.
+    pub lba:      u64,
+    /// The likely cause.
+    pub kind:     MismatchKind,
+    /// What the sector should contain.
+    pub expected: Vec<u8>,
+    /// What it actually contains.
+    pub actual:   Vec<u8>,
+}
+
+impl Mismatch {
+    /// Byte offset within the sector of the first difference.
+    pub fn first_difference(&self) -> usize {
+        self.expected
+            .iter()
+            .zip(self.actual.iter())
+            .position(|(e, a)| e != a)
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "LBA {}: {} (first difference at byte {})",
+            self.lba,
+            self.kind,
+            self.first_difference()
+        )
+    }
+}
+
+/// The likely cause of a [`Mismatch`], diagnosed from the sector's contents.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum MismatchKind {
+    /// The sector contains data intended for a different sector.
+    Misdirected {
+        /// The LBA that the data was meant for.
+        lba:  u64,
+        /// The seed that the data was written with.
+        seed: u64,
+    },
+    /// Only part of the sector was written: its header or trailer matches, but not the rest.
+    Torn,
+    /// The sector contains only zeros, as if it was never written.
+    Lost,
+    /// The sector contains data from an earlier pass with a different seed, as if the latest
+    /// write was lost.
+    Stale {
+        /// The seed that the data was written with.
+        seed: u64,
+    },
+    /// The sector contains something else entirely.
+    Corrupt,
+}
+
+impl fmt::Display for MismatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MismatchKind::Misdirected { lba, seed } => {
+                write!(f, "misdirected write for LBA {lba} with seed {seed:#x}")
+            }
+            MismatchKind::Torn => f.write_str("torn write"),
+            MismatchKind::Lost => f.write_str("lost write"),
+            MismatchKind::Stale { seed } => {
+                write!(f, "stale data with seed {seed:#x}")
+            }
+            MismatchKind::Corrupt => f.write_str("corrupt data"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTORS: u64 = 64;
+
+    /// A filled temporary file, to test the verifier without md
+    fn filled(v: &Verifier) -> tempfile::NamedTempFile {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        v.fill(tf.as_file(), 0..SECTORS).unwrap();
+        tf
+    }
+
+    fn single_mismatch(report: &VerifyReport, lba: u64) -> MismatchKind {
+        assert_eq!(report.checked, SECTORS);
+        assert_eq!(report.mismatched, 1, "{report}");
+        assert_eq!(report.mismatches[0].lba, lba);
+        report.mismatches[0].kind
+    }
+
+    #[test]
+    fn corrupt() {
+        let v = Verifier::new(1, 512);
+        let tf = filled(&v);
+        tf.as_file().write_all_at(&[0xff; 512], 7 * 512).unwrap();
+        let report = v.verify(tf.as_file(), 0..SECTORS).unwrap();
+        assert_eq!(single_mismatch(&report, 7), MismatchKind::Corrupt);
+        let m = &report.mismatches[0];
+        assert_eq!(m.expected, v.expected(7));
+        assert_eq!(m.actual, [0xff; 512]);
+        assert_eq!(m.first_difference(), 0);
+    }
+
+    /// Verifying a different range than was filled is deterministic
+    #[test]
+    fn deterministic() {
+        let v = Verifier::new(1, 512);
+        let tf = filled(&v);
+        let report =
+            Verifier::new(1, 512).verify(tf.as_file(), 16..32).unwrap();
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.checked, 16);
+    }
+
+    #[test]
+    fn lost() {
+        let v = Verifier::new(1, 512);
+        let tf = filled(&v);
+        tf.as_file().write_all_at(&[0; 512], 3 * 512).unwrap();
+        let report = v.verify(tf.as_file(), 0..SECTORS).unwrap();
+        assert_eq!(single_mismatch(&report, 3), MismatchKind::Lost);
+    }
+
+    /// Beyond the limit, mismatches are counted but not recorded
+    #[test]
+    fn max_mismatches() {
+        let tf = filled(&Verifier::new(1, 512));
+        let v = Verifier::new(2, 512).max_mismatches(4);
+        let report = v.verify(tf.as_file(), 0..SECTORS).unwrap();
+        assert_eq!(report.mismatched, SECTORS);
+        assert_eq!(report.mismatches.len(), 4);
+        assert!(report.to_string().ends_with("... and 60 more"));
+    }
+
+    #[test]
+    fn misdirected() {
+        let v = Verifier::new(1, 512);
+        let tf = filled(&v);
+        tf.as_file().write_all_at(&v.expected(9), 5 * 512).unwrap();
+        let report = v.verify(tf.as_file(), 0..SECTORS).unwrap();
+        assert_eq!(
+            single_mismatch(&report, 5),
+            MismatchKind::Misdirected { lba: 9, seed: 1 }
+        );
+    }
+
+    #[test]
+    fn stale() {
+        let old = Verifier::new(1, 512);
+        let tf = filled(&old);
+        let new = Verifier::new(2, 512);
+        new.fill(tf.as_file(), 0..SECTORS).unwrap();
+        tf.as_file()
+            .write_all_at(&old.expected(11), 11 * 512)
+            .unwrap();
+        let report = new.verify(tf.as_file(), 0..SECTORS).unwrap();
+        assert_eq!(
+            single_mismatch(&report, 11),
+            MismatchKind::Stale { seed: 1 }
+        );
+    }
+
+    /// Both the head and the tail of a sector can be torn
+    #[test]
+    fn torn() {
+        let v = Verifier::new(1, 4096);
+        let tf = filled(&v);
+        tf.as_file()
+            .write_all_at(&[0xff; 2048], 2 * 4096 + 2048)
+            .unwrap();
+        tf.as_file().write_all_at(&[0xff; 2048], 4 * 4096).unwrap();
+        let report = v.verify(tf.as_file(), 0..SECTORS).unwrap();
+        assert_eq!(report.mismatched, 2, "{report}");
+        assert_eq!(report.mismatches[0].lba, 2);
+        assert_eq!(report.mismatches[0].kind, MismatchKind::Torn);
+        assert_eq!(report.mismatches[0].first_difference(), 2048);
+        assert_eq!(report.mismatches[1].lba, 4);
+        assert_eq!(report.mismatches[1].kind, MismatchKind::Torn);
+    }
+
+    /// Reading past the end of the file is an error, not a mismatch
+    #[test]
+    fn truncated() {
+        let v = Verifier::new(1, 512);
+        let tf = filled(&v);
+        let e = v.verify(tf.as_file(), 0..SECTORS + 1).unwrap_err();
+        assert_eq!(e.operation(), Operation::ReadDisk);
+    }
+}
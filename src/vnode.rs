@@ -0,0 +1,88 @@
+//! Managing the backing files of vnode-backed devices
+use std::{
+    fs,
+    io,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    path::Path,
+};
+
+/// How to extend a vnode-backed device's backing file when growing the device.
+///
+/// Used by [`Md::resize_with_file`](crate::Md::resize_with_file).
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum FileGrowth {
+    /// Extend the file without allocating any storage.  The new space reads as zeros.
+    #[default]
+    Sparse,
+    /// Allocate storage for the new space up front, so writes to it won't fail with `ENOSPC`.
+    ///
+    /// Uses `posix_fallocate`, or, on file systems that don't support it like ZFS, writes zeros.
+    Preallocate,
+}
+
+/// Extend a file to `len` bytes, if it's shorter than that.  Returns its original length.
+pub(crate) fn grow(
+    path: &Path,
+    len: u64,
+    growth: FileGrowth,
+) -> io::Result<u64> {
+    let f = fs::OpenOptions::new().write(true).open(path)?;
+    let oldlen = f.metadata()?.len();
+    if oldlen >= len {
+        return Ok(oldlen);
+    }
+    let r = match growth {
+        FileGrowth::Sparse => f.set_len(len),
+        FileGrowth::Preallocate => preallocate(&f, oldlen, len),
+    };
+    if let Err(e) = r {
+        // Don't leave the file partially extended.
+        let _ = f.set_len(oldlen);
+        return Err(e);
+    }
+    Ok(oldlen)
+}
+
+/// Allocate storage for the range from `start` to `end`.
+fn preallocate(f: &fs::File, start: u64, end: u64) -> io::Result<()> {
+    let r = unsafe {
+        libc::posix_fallocate(
+            f.as_raw_fd(),
+            start as libc::off_t,
+            (end - start) as libc::off_t,
+        )
+    };
+    match r {
+        0 => Ok(()),
+        libc::EINVAL | libc::EOPNOTSUPP => {
+            // Unsupported by this file system.  Fall back to writing zeros.
+            const CHUNK: u64 = 1 << 20;
+            let zeros = vec![0u8; CHUNK as usize];
+            let mut ofs = start;
+            while ofs < end {
+                let len = CHUNK.min(end - ofs);
+                f.write_all_at(&zeros[..len as usize], ofs)?;
+                ofs += len;
+            }
+            Ok(())
+        }
+        e => Err(io::Error::from_raw_os_error(e)),
+    }
+}
+
+/// Truncate a file to `len` bytes, if it's longer than that.
+pub(crate) fn shrink(path: &Path, len: u64) -> io::Result<()> {
+    let f = fs::OpenOptions::new().write(true).open(path)?;
+    if f.metadata()?.len() > len {
+        f.set_len(len)?;
+    }
+    Ok(())
+}
+
+/// Restore a file to a previous length after a failed resize.
+pub(crate) fn restore(path: &Path, len: u64) {
+    if let Ok(f) = fs::OpenOptions::new().write(true).open(path) {
+        let _ = f.set_len(len);
+    }
+}
@@ -3,27 +3,16 @@ use std::{
     fs,
     mem,
     os::{
-        fd::AsRawFd,
+        fd::OwnedFd,
         unix::{ffi::OsStrExt, fs::FileTypeExt},
     },
     path::Path,
     process::Command,
-    sync::OnceLock,
+    sync::{Arc, OnceLock},
 };
 
 use cfg_if::cfg_if;
 use mdconfig::*;
-use nix::{ioctl_read, ioctl_readwrite};
-
-cfg_if! {
-    if #[cfg(target_pointer_width = "64")] {
-        mod ffi64;
-        use ffi64 as ffi;
-    } else if #[cfg(target_pointer_width = "32")] {
-        mod ffi32;
-        use ffi32 as ffi;
-    }
-}
 
 static FBSD15: OnceLock<bool> = OnceLock::new();
 
@@ -58,11 +47,6 @@ macro_rules! require_fbsd15 {
     };
 }
 
-ioctl_read!(diocgsectorsize, 'd', 128, nix::libc::c_uint);
-ioctl_read!(diocfwsectors, 'd', 130, nix::libc::c_uint);
-ioctl_read!(diocfwheads, 'd', 131, nix::libc::c_uint);
-ioctl_readwrite!(diocgattr, 'd', 142, ffi::diocgattr_arg);
-
 #[derive(Clone, Debug)]
 struct MdData {
     name:    String,
@@ -93,6 +77,53 @@ fn list_unit(unit: u32) -> MdData {
     }
 }
 
+mod control {
+    use super::*;
+
+    #[test]
+    fn create() {
+        let ctl = Arc::new(MdControl::open().unwrap());
+        let md = ctl.create(Builder::swap(1 << 20)).unwrap();
+        assert_eq!(Arc::strong_count(&ctl), 2);
+
+        md.resize(1 << 21, false).unwrap();
+        assert_eq!(ctl.query(md.unit()).unwrap().mediasize, 1 << 21);
+        let unit = md.unit();
+        drop(md);
+        assert_eq!(Arc::strong_count(&ctl), 1);
+        assert!(!ctl.list().unwrap().contains(&unit));
+    }
+
+    #[test]
+    fn from_fd() {
+        let fd = OwnedFd::from(fs::File::open("/dev/mdctl").unwrap());
+        let ctl = MdControl::from(fd);
+        let md = Builder::null(1 << 20).create().unwrap();
+        assert!(ctl.list().unwrap().contains(&md.unit()));
+        let unit = md.unit();
+        mem::forget(md);
+        ctl.detach(unit, false).unwrap();
+    }
+
+    #[test]
+    fn not_loaded() {
+        let e =
+            MdControl::open_path(Path::new("/nonexistent/mdctl")).unwrap_err();
+        assert!(matches!(e, MdError::NotLoaded(_)));
+        assert_eq!(e.operation(), Operation::Open);
+    }
+
+    #[test]
+    fn set_control() {
+        let ctl = Arc::new(MdControl::open().unwrap());
+        let mut md = Builder::null(1 << 20).create().unwrap();
+        md.set_control(ctl.clone());
+        assert_eq!(Arc::strong_count(&ctl), 2);
+        drop(md);
+        assert_eq!(Arc::strong_count(&ctl), 1);
+    }
+}
+
 mod create {
     use super::*;
 
@@ -159,6 +190,26 @@ mod create {
         assert_eq!(data.label, "-");
     }
 
+    #[test]
+    fn force() {
+        require_fbsd15!();
+
+        let md = Builder::malloc(1 << 20).force(true).create().unwrap();
+
+        let data = list_unit(md.unit());
+        assert_eq!(data.options, "force");
+    }
+
+    #[test]
+    fn missing_backing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.img");
+        let e = Builder::vnode(&path).create().unwrap_err();
+        assert_eq!(e.operation(), Operation::Attach);
+        assert_eq!(e.path(), Some(path.as_path()));
+        assert_eq!(libc::ENOENT, e.raw_os_error().unwrap());
+    }
+
     #[test]
     fn mustdealloc() {
         require_fbsd15!();
@@ -192,6 +243,18 @@ mod create {
         assert_eq!(data.label, "-");
     }
 
+    #[test]
+    fn options() {
+        let md = Builder::swap(1 << 20)
+            .options(MdOptions::RESERVE | MdOptions::FORCE)
+            .create()
+            .unwrap();
+
+        let info = md.query().unwrap();
+        assert!(info.options.contains(MdOptions::RESERVE | MdOptions::FORCE));
+        assert!(!info.options.contains(MdOptions::CLUSTER));
+    }
+
     #[test]
     fn readonly() {
         require_fbsd15!();
@@ -217,10 +280,8 @@ mod create {
     #[test]
     fn sectorsize() {
         let md = Builder::swap(1 << 20).sectorsize(2048).create().unwrap();
-        let mut sectorsize = 0u32;
-        let f = fs::File::open(md.path()).unwrap();
-        unsafe { diocgsectorsize(f.as_raw_fd(), &mut sectorsize).unwrap() };
-        assert_eq!(sectorsize, 2048);
+        let disk = md.open(fs::OpenOptions::new().read(true)).unwrap();
+        assert_eq!(disk.sectorsize().unwrap(), 2048);
     }
 
     // The kernel requires both of sectors_per_track and heads to be set.  If only one is set, it
@@ -232,16 +293,9 @@ mod create {
             .heads_per_cylinder(69)
             .create()
             .unwrap();
-        let mut sectors = 0u32;
-        let mut heads = 0u32;
-        let f = fs::File::open(md.path()).unwrap();
-        unsafe {
-            diocfwsectors(f.as_raw_fd(), &mut sectors).unwrap();
-            diocfwheads(f.as_raw_fd(), &mut heads).unwrap();
-        }
-        drop(f);
-        assert_eq!(sectors, 42);
-        assert_eq!(heads, 69);
+        let disk = md.open(fs::OpenOptions::new().read(true)).unwrap();
+        assert_eq!(disk.fwsectors().unwrap(), 42);
+        assert_eq!(disk.fwheads().unwrap(), 69);
     }
 
     #[test]
@@ -264,34 +318,78 @@ mod create {
         list_unit(md.unit());
     }
 
+    #[test]
+    fn unit_in_use() {
+        let md = Builder::null(1 << 20).unit(667).create().unwrap();
+
+        let e = Builder::null(1 << 20).unit(md.unit()).create().unwrap_err();
+        assert!(matches!(e, MdError::UnitInUse(_)));
+        assert_eq!(e.unit(), Some(md.unit()));
+    }
+
+    #[test]
+    fn unit_range() {
+        let md = Builder::null(1 << 20)
+            .unit_range(670..680)
+            .create()
+            .unwrap();
+        assert!((670..680).contains(&md.unit()));
+    }
+
+    /// Units already in use are skipped, and if all are in use creation fails
+    #[test]
+    fn unit_range_exhausted() {
+        let md0 = Builder::null(1 << 20).unit(680).create().unwrap();
+        let md1 = Builder::null(1 << 20)
+            .unit_range(680..682)
+            .create()
+            .unwrap();
+        assert_eq!(md1.unit(), 681);
+        let e = Builder::null(1 << 20)
+            .unit_range(680..682)
+            .create()
+            .unwrap_err();
+        assert!(matches!(e, MdError::UnitInUse(_)));
+        drop(md0);
+    }
+
+    #[test]
+    fn unit_range_from_env() {
+        const VAR: &str = "MDCONFIG_TEST_UNIT_RANGE_SLOT";
+        // Safe because no other test touches this variable.
+        unsafe { std::env::set_var(VAR, "7") };
+        // 7 modulo 5 chunks is chunk 2, units 694 through 696.
+        let md = Builder::null(1 << 20)
+            .unit_range_from_env(VAR, 688..703, 3)
+            .create()
+            .unwrap();
+        assert!((694..697).contains(&md.unit()));
+        // Without the variable, the whole range is used.
+        let md = Builder::null(1 << 20)
+            .unit_range_from_env("MDCONFIG_TEST_UNSET", 703..706, 1)
+            .create()
+            .unwrap();
+        assert!((703..706).contains(&md.unit()));
+    }
+
     #[test]
     fn verify() {
         let tf = tempfile::NamedTempFile::new().unwrap();
         tf.as_file().set_len(1 << 21).unwrap();
         let md = Builder::vnode(tf.path()).verify(true).create().unwrap();
 
-        let f = fs::File::open(md.path()).unwrap();
-        let attrname = OsStr::new("MNT::verified");
-        let verified = unsafe {
-            let mut arg: ffi::diocgattr_arg = mem::zeroed();
-            arg.len = mem::size_of::<libc::c_int>() as i32;
-            let attrp = attrname.as_bytes().as_ptr() as *const i8;
-            arg.name.as_mut_ptr().copy_from(attrp, attrname.len());
-            let r = diocgattr(f.as_raw_fd(), &mut arg);
-            cfg_if! {
-                if #[cfg(target_pointer_width = "32")] {
-                    if r == Err(nix::errno::Errno::ENOTTY) {
-                        // This error usually means that we're running in 32-bit emulation mode.
-                        // DIOCGATTR does not work in 32-bit emulation, so skip this test.
-                        return
-                    }
+        let disk = md.open(fs::OpenOptions::new().read(true)).unwrap();
+        let r = disk.attr_int("MNT::verified");
+        cfg_if! {
+            if #[cfg(target_pointer_width = "32")] {
+                if r.as_ref().is_err_and(|e| e.raw_os_error() == Some(libc::ENOTTY)) {
+                    // This error usually means that we're running in 32-bit emulation mode.
+                    // DIOCGATTR does not work in 32-bit emulation, so skip this test.
+                    return
                 }
             }
-            r.unwrap();
-            arg.value.i
-        };
-        assert!(verified != 0);
-        drop(f);
+        }
+        assert!(r.unwrap() != 0);
     }
 
     #[test]
@@ -307,6 +405,21 @@ mod create {
         assert_eq!(Path::new(&data.path), tf.path());
     }
 
+    #[test]
+    fn size_str() {
+        let md = Builder::swap(0)
+            .size_str("1024s")
+            .sectorsize(4096)
+            .create()
+            .unwrap();
+
+        let data = list_unit(md.unit());
+        assert_eq!(data.size, "4096K");
+        let info = md.query().unwrap();
+        assert_eq!(info.mediasize, 4 << 20);
+        assert_eq!(Size::Bytes(info.mediasize).to_string(), data.size);
+    }
+
     /// Create a vnode-backed MD device, but override the default size
     #[test]
     fn vnode_with_size() {
@@ -319,7 +432,299 @@ mod create {
     }
 }
 
+mod destroy_timeout {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// On timeout, the Md is returned along with the last error
+    #[test]
+    fn busy() {
+        let timeout = Duration::from_millis(200);
+        let md = Builder::swap(1 << 21).create().unwrap();
+        let _f = fs::File::open(md.path()).unwrap();
+        let start = Instant::now();
+        let (md, e) = md.destroy_timeout(timeout).unwrap_err();
+        assert!(start.elapsed() >= timeout);
+        assert!(matches!(e, MdError::Busy(_)));
+        assert_eq!(md.query().unwrap().unit, md.unit());
+    }
+
+    #[test]
+    fn force_on_timeout() {
+        let md = Builder::swap(1 << 21).create().unwrap();
+        let unit = md.unit();
+        let _f = fs::File::open(md.path()).unwrap();
+        let opts = DestroyOptions::new(Duration::from_millis(100))
+            .backoff(Duration::from_millis(1), Duration::from_millis(20))
+            .force_on_timeout(true);
+        md.destroy_with(&opts).unwrap();
+        assert!(matches!(
+            MdRef::from_unit(unit).unwrap_err(),
+            MdError::NoSuchUnit(_)
+        ));
+    }
+
+    /// Errors other than EBUSY are not retried
+    #[test]
+    fn no_such_unit() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        MdControl::open().unwrap().detach(md.unit(), true).unwrap();
+        let start = Instant::now();
+        let (md, e) = md.destroy_timeout(Duration::from_secs(60)).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert!(matches!(e, MdError::NoSuchUnit(_)));
+        mem::forget(md);
+    }
+
+    #[test]
+    fn ok() {
+        let md = Builder::swap(1 << 21).create().unwrap();
+        md.destroy_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
+
+mod disk {
+    use super::*;
+
+    fn open(md: &Md) -> MdDisk {
+        md.open(fs::OpenOptions::new().read(true).write(true))
+            .unwrap()
+    }
+
+    #[test]
+    fn alloc_sectors() {
+        let md = Builder::malloc(1 << 20).sectorsize(2048).create().unwrap();
+        let buf = open(&md).alloc_sectors(3);
+        assert_eq!(buf.len(), 6144);
+        assert_eq!(buf.as_ptr() as usize % 2048, 0);
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn attr_str() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let disk = open(&md);
+        assert_eq!(
+            disk.attr_str("GEOM::ident").unwrap(),
+            disk.ident().unwrap()
+        );
+    }
+
+    #[test]
+    fn attr_too_long() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let disk = open(&md);
+        let e = disk.attr_int(&"x".repeat(64)).unwrap_err();
+        assert_eq!(e.operation(), Operation::DiskIoctl("DIOCGATTR"));
+    }
+
+    /// Only vnode-backed devices have a backing file
+    #[test]
+    fn backing_allocated() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        assert_eq!(md.backing_allocated().unwrap(), None);
+    }
+
+    #[test]
+    fn delete() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let mut disk = open(&md);
+        disk.write_all(&[0xa5; 8192]).unwrap();
+        disk.delete(4096..8192).unwrap();
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0u8; 8192];
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..4096], [0xa5; 4096]);
+        assert_eq!(buf[4096..], [0; 4096]);
+    }
+
+    /// An empty range is a no-op
+    #[test]
+    fn delete_empty() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        open(&md).delete(512..512).unwrap();
+    }
+
+    #[test]
+    fn delete_misaligned() {
+        let md = Builder::malloc(1 << 20).sectorsize(4096).create().unwrap();
+        let disk = open(&md);
+        let e = disk.delete(512..4096).unwrap_err();
+        assert!(matches!(e, MdError::Misaligned(_)));
+        let e = disk.delete(0..4097).unwrap_err();
+        assert!(matches!(e, MdError::Misaligned(_)));
+    }
+
+    #[test]
+    fn delete_out_of_range() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let disk = open(&md);
+        let e = disk.delete((1 << 20) - 512..(1 << 20) + 512).unwrap_err();
+        assert!(matches!(e, MdError::OutOfRange(_)));
+    }
+
+    /// Deleting from a vnode-backed device punches a hole in its file
+    #[test]
+    fn delete_vnode() {
+        use std::{
+            io::Write,
+            os::unix::fs::FileExt,
+            thread,
+            time::{Duration, Instant},
+        };
+
+        const SIZE: u64 = 1 << 22;
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        tf.as_file()
+            .write_all_at(&vec![0xa5; SIZE as usize], 0)
+            .unwrap();
+        tf.as_file().sync_all().unwrap();
+        let md = Builder::vnode(tf.path()).create().unwrap();
+        let before = md.backing_allocated().unwrap().unwrap();
+        let mut disk = open(&md);
+        disk.delete(0..SIZE).unwrap();
+        disk.flush().unwrap();
+        // Some file systems, like ZFS, update st_blocks lazily.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            let after = md.backing_allocated().unwrap().unwrap();
+            if after < before {
+                break;
+            }
+            assert!(Instant::now() < deadline, "{after} >= {before}");
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn mediasize() {
+        let md = Builder::swap(1 << 20).create().unwrap();
+        let disk = open(&md);
+        assert_eq!(disk.mediasize().unwrap(), 1 << 20);
+        md.resize(1 << 21, false).unwrap();
+        assert_eq!(disk.mediasize().unwrap(), 1 << 21);
+    }
+
+    #[test]
+    fn read_write() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let mut disk = open(&md);
+        disk.write_all(&[0xa5; 512]).unwrap();
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = [0u8; 512];
+        disk.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0xa5; 512]);
+    }
+
+    /// Opening a device that was detached reports NoSuchUnit
+    #[test]
+    fn no_such_unit() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        MdControl::open().unwrap().detach(md.unit(), true).unwrap();
+        let e = md.open(fs::OpenOptions::new().read(true)).unwrap_err();
+        assert!(matches!(e, MdError::NoSuchUnit(_)));
+        mem::forget(md);
+    }
+
+    #[test]
+    fn sectors() {
+        let md = Builder::malloc(1 << 20).sectorsize(2048).create().unwrap();
+        let disk = open(&md);
+        let mut wbuf = disk.alloc_sectors(2);
+        wbuf.fill(0xa5);
+        disk.write_sectors(3, &wbuf).unwrap();
+        let mut rbuf = disk.alloc_sectors(4);
+        disk.read_sectors(2, &mut rbuf).unwrap();
+        assert_eq!(rbuf[..2048], [0; 2048]);
+        assert_eq!(rbuf[2048..6144], wbuf[..]);
+        assert_eq!(rbuf[6144..], [0; 2048]);
+    }
+
+    /// The last sector can be accessed, but not beyond it
+    #[test]
+    fn sectors_last() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let disk = open(&md);
+        let mut buf = disk.alloc_sectors(1);
+        disk.read_sectors(2047, &mut buf).unwrap();
+        let e = disk.read_sectors(2048, &mut buf).unwrap_err();
+        assert!(matches!(e, MdError::OutOfRange(_)));
+        let e = disk.write_sectors(u64::MAX, &buf).unwrap_err();
+        assert!(matches!(e, MdError::OutOfRange(_)));
+    }
+
+    #[test]
+    fn sectors_misaligned() {
+        let md = Builder::malloc(1 << 20).sectorsize(2048).create().unwrap();
+        let disk = open(&md);
+        let mut buf = vec![0u8; 512];
+        let e = disk.read_sectors(0, &mut buf).unwrap_err();
+        assert!(matches!(e, MdError::Misaligned(_)));
+        assert_eq!(e.operation(), Operation::ReadDisk);
+        let e = disk.write_sectors(0, &buf).unwrap_err();
+        assert!(matches!(e, MdError::Misaligned(_)));
+        assert_eq!(e.operation(), Operation::WriteDisk);
+    }
+
+    #[test]
+    fn sectors_vectored() {
+        use std::io::{IoSlice, IoSliceMut};
+
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let disk = open(&md);
+        let a = [1u8; 512];
+        let b = [2u8; 1024];
+        let mut wbufs = [IoSlice::new(&a), IoSlice::new(&b)];
+        disk.write_sectors_vectored(10, &mut wbufs).unwrap();
+
+        let mut c = [0u8; 1024];
+        let mut d = [0u8; 512];
+        let mut rbufs = [IoSliceMut::new(&mut c), IoSliceMut::new(&mut d)];
+        disk.read_sectors_vectored(10, &mut rbufs).unwrap();
+        assert_eq!(c[..512], [1; 512]);
+        assert_eq!(c[512..], [2; 512]);
+        assert_eq!(d, [2; 512]);
+    }
+
+    /// Every buffer must be a whole number of sectors, not just the total
+    #[test]
+    fn sectors_vectored_misaligned() {
+        use std::io::IoSlice;
+
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let disk = open(&md);
+        let a = [0u8; 256];
+        let mut bufs = [IoSlice::new(&a), IoSlice::new(&a)];
+        let e = disk.write_sectors_vectored(0, &mut bufs).unwrap_err();
+        assert!(matches!(e, MdError::Misaligned(_)));
+    }
+
+    #[test]
+    fn stripe() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let disk = open(&md);
+        assert_eq!(disk.stripesize().unwrap(), 0);
+        assert_eq!(disk.stripeoffset().unwrap(), 0);
+    }
+
+    #[test]
+    fn unit() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        assert_eq!(open(&md).unit(), md.unit());
+    }
+}
+
 mod drop {
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
     use super::*;
 
     /// Test that the device gets deallocated on drop
@@ -338,56 +743,869 @@ mod drop {
             assert!(old_mtime != new_mtime);
         }
     }
-}
-
-mod resize {
-    use super::*;
 
+    /// Errors during drop go to the hook, rather than panicking
     #[test]
-    fn down() {
-        let md = Builder::swap(1 << 21).create().unwrap();
-
-        let data = list_unit(md.unit());
-        assert_eq!(data.size, "2048K");
+    fn hook() {
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let errors2 = errors.clone();
+        let md = Builder::null(1 << 20)
+            .on_drop_error(move |e| errors2.lock().unwrap().push(e.unit()))
+            .create()
+            .unwrap();
+        let unit = md.unit();
+        // Destroy the device out from under the Md.
+        MdControl::open().unwrap().detach(unit, true).unwrap();
+        drop(md);
+        assert_eq!(*errors.lock().unwrap(), vec![Some(unit)]);
+    }
 
-        md.resize(1 << 20, true).unwrap();
-        let data = list_unit(md.unit());
-        assert_eq!(data.size, "1024K");
+    #[test]
+    fn leak() {
+        let md = Builder::null(1 << 20)
+            .drop_policy(DropPolicy::Leak)
+            .create()
+            .unwrap();
+        let unit = md.unit();
+        drop(md);
+        MdRef::from_unit(unit).unwrap();
+        MdControl::open().unwrap().detach(unit, true).unwrap();
     }
 
     #[test]
-    fn up() {
-        let md = Builder::swap(1 << 20).create().unwrap();
+    fn policy() {
+        let mut md = Builder::null(1 << 20)
+            .drop_policy(DropPolicy::Leak)
+            .create()
+            .unwrap();
+        assert_eq!(md.drop_policy(), DropPolicy::Leak);
+        md.set_drop_policy(DropPolicy::TryOnly);
+        assert_eq!(md.drop_policy(), DropPolicy::TryOnly);
+        assert_eq!(
+            Builder::null(1 << 20).create().unwrap().drop_policy(),
+            DropPolicy::Force
+        );
+    }
 
-        let data = list_unit(md.unit());
-        assert_eq!(data.size, "1024K");
+    /// A busy device is forcefully detached once the grace period expires
+    #[test]
+    fn try_then_force() {
+        let grace = Duration::from_millis(500);
+        let md = Builder::swap(1 << 21)
+            .drop_policy(DropPolicy::TryThenForce(grace))
+            .create()
+            .unwrap();
+        let unit = md.unit();
+        let _f = fs::File::open(md.path()).unwrap();
+        let start = Instant::now();
+        drop(md);
+        assert!(start.elapsed() >= grace);
+        assert!(matches!(
+            MdRef::from_unit(unit).unwrap_err(),
+            MdError::NoSuchUnit(_)
+        ));
+    }
 
-        md.resize(1 << 21, false).unwrap();
-        let data = list_unit(md.unit());
-        assert_eq!(data.size, "2048K");
+    /// A busy device is left attached, without panicking
+    #[test]
+    fn try_only() {
+        let md = Builder::swap(1 << 21)
+            .drop_policy(DropPolicy::TryOnly)
+            .create()
+            .unwrap();
+        let unit = md.unit();
+        let f = fs::File::open(md.path()).unwrap();
+        drop(md);
+        drop(f);
+        MdControl::open().unwrap().detach(unit, true).unwrap();
     }
 }
 
-mod try_destroy {
-    use std::{
-        thread::sleep,
-        time::{Duration, Instant},
-    };
+mod export {
+    use std::os::unix::fs::{FileExt, MetadataExt};
 
     use super::*;
 
+    /// A malloc device with a little data near the start and a lot of zeros
+    fn mostly_empty() -> Md {
+        let md = Builder::malloc(1 << 24).create().unwrap();
+        let disk = md.open(fs::OpenOptions::new().write(true)).unwrap();
+        disk.write_sectors(8, &[0xa5; 8192]).unwrap();
+        disk.write_sectors(1024, &[0x5a; 512]).unwrap();
+        md
+    }
+
     #[test]
-    fn ebusy() {
-        let md = Builder::swap(1 << 21).create().unwrap();
-        let _f = fs::File::open(md.path()).unwrap();
-        let (_md, e) = md.try_destroy().unwrap_err();
-        assert_eq!(libc::EBUSY, e.raw_os_error().unwrap());
+    fn contents() {
+        let md = mostly_empty();
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        md.export_to(tf.path()).unwrap();
+        let mut expected = vec![0u8; 1 << 24];
+        let disk = md.open(fs::OpenOptions::new().read(true)).unwrap();
+        disk.read_sectors(0, &mut expected).unwrap();
+        assert_eq!(fs::read(tf.path()).unwrap(), expected);
     }
 
+    /// A chunk size larger than the device is limited to the device's size
     #[test]
-    fn ok() {
-        let mut md = Builder::swap(1 << 21).create().unwrap();
-        let timeout = Duration::from_secs(5);
+    fn huge_chunk_size() {
+        let md = mostly_empty();
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let opts = ExportOptions::default().chunk_size(usize::MAX);
+        let mut calls = Vec::new();
+        md.export_with(tf.path(), &opts, |done, total| {
+            calls.push((done, total))
+        })
+        .unwrap();
+        assert_eq!(calls, [(1 << 24, 1 << 24)]);
+    }
+
+    #[test]
+    fn progress() {
+        let md = mostly_empty();
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let opts = ExportOptions::default().chunk_size(1 << 22);
+        let mut calls = Vec::new();
+        md.export_with(tf.path(), &opts, |done, total| {
+            calls.push((done, total))
+        })
+        .unwrap();
+        let total = 1 << 24;
+        assert_eq!(
+            calls,
+            [
+                (1 << 22, total),
+                (2 << 22, total),
+                (3 << 22, total),
+                (total, total)
+            ]
+        );
+    }
+
+    /// Zeros are not written, so the output file is sparse
+    #[test]
+    fn sparse() {
+        let md = mostly_empty();
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let stats = md.export_to(tf.path()).unwrap();
+        assert_eq!(stats.bytes_read, 1 << 24);
+        assert_eq!(stats.bytes_written, 8192 + 4096);
+        assert_eq!(stats.bytes_skipped, (1 << 24) - 8192 - 4096);
+        assert_eq!(stats.file_len, 1 << 24);
+        let meta = fs::metadata(tf.path()).unwrap();
+        assert_eq!(meta.len(), 1 << 24);
+        assert!(meta.blocks() * 512 < 1 << 20);
+    }
+
+    #[test]
+    fn truncate_trailing_zeros() {
+        let md = mostly_empty();
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let opts = ExportOptions::default().truncate_trailing_zeros(true);
+        let stats = md.export_with(tf.path(), &opts, |_, _| ()).unwrap();
+        // The last data is in the 4096-byte block containing sector 1024.
+        assert_eq!(stats.file_len, 1024 * 512 + 4096);
+        let f = fs::File::open(tf.path()).unwrap();
+        assert_eq!(f.metadata().unwrap().len(), stats.file_len);
+        let mut buf = [0u8; 512];
+        f.read_exact_at(&mut buf, 1024 * 512).unwrap();
+        assert_eq!(buf, [0x5a; 512]);
+    }
+
+    /// An entirely empty device exports to an empty file
+    #[test]
+    fn truncate_empty() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        let opts = ExportOptions::default().truncate_trailing_zeros(true);
+        let stats = md.export_with(tf.path(), &opts, |_, _| ()).unwrap();
+        assert_eq!(stats.bytes_written, 0);
+        assert_eq!(fs::metadata(tf.path()).unwrap().len(), 0);
+    }
+}
+
+mod fork {
+    use super::*;
+
+    /// Fork, run `f` in the child, and wait for it to exit.
+    ///
+    /// The child must avoid allocating, since the parent is multithreaded.
+    fn in_child(f: impl FnOnce()) {
+        match unsafe { libc::fork() } {
+            0 => {
+                f();
+                unsafe { libc::_exit(0) };
+            }
+            pid => {
+                let mut status = 0;
+                assert_eq!(pid, unsafe { libc::waitpid(pid, &mut status, 0) });
+                assert!(libc::WIFEXITED(status));
+            }
+        }
+    }
+
+    /// A forked child's copy of an Md doesn't detach the parent's device
+    #[test]
+    fn child_drop() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        let mut md = Some(md);
+        in_child(|| drop(md.take()));
+        md.unwrap().query().unwrap();
+    }
+
+    /// After transfer_to_child, the child detaches the device
+    #[test]
+    fn transfer_to_child() {
+        let ctl = Arc::new(MdControl::open().unwrap());
+        let mut md = ctl.create(Builder::null(1 << 20)).unwrap();
+        let unit = md.unit();
+        md.set_drop_policy(DropPolicy::Leak);
+        let mut md = Some(md);
+        in_child(|| {
+            let mut md = md.take().unwrap();
+            md.transfer_to_child();
+            md.set_drop_policy(DropPolicy::Force);
+            drop(md);
+        });
+        assert!(matches!(ctl.query(unit), Err(MdError::NoSuchUnit(_))));
+    }
+}
+
+mod from_unit {
+    use super::*;
+
+    #[test]
+    fn enoent() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        let unit = md.unit();
+        drop(md);
+        let e = Md::from_unit(unit).unwrap_err();
+        assert!(matches!(e, MdError::NoSuchUnit(_)));
+    }
+
+    #[test]
+    fn from_path() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        let adopted = MdRef::from_path(md.path()).unwrap();
+        assert_eq!(adopted.unit(), md.unit());
+        assert_eq!(adopted.path(), md.path());
+    }
+
+    #[test]
+    fn not_md() {
+        let e = Md::from_path(Path::new("/dev/null")).unwrap_err();
+        assert!(matches!(e, MdError::Other(_)));
+        assert_eq!(e.path(), Some(Path::new("/dev/null")));
+        let e = std::io::Error::from(e);
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(e.get_ref().unwrap().is::<MdError>());
+    }
+
+    /// An owned handle should detach the device on drop
+    #[test]
+    fn owned() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        let unit = md.unit();
+        mem::forget(md);
+        let md = Md::from_unit(unit).unwrap();
+        drop(md);
+        assert!(!mdconfig::list().unwrap().contains(&unit));
+    }
+
+    /// A borrowed handle must not detach the device on drop
+    #[test]
+    fn borrowed() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        let borrowed = MdRef::from_unit(md.unit()).unwrap();
+        borrowed.resize(1 << 21, false).unwrap();
+        drop(borrowed);
+        assert_eq!(md.query().unwrap().mediasize, 1 << 21);
+    }
+}
+
+mod list {
+    use super::*;
+
+    #[test]
+    fn list() {
+        let md0 = Builder::null(1 << 20).create().unwrap();
+        let md1 = Builder::null(1 << 20).create().unwrap();
+
+        let units = mdconfig::list().unwrap();
+        assert!(units.contains(&md0.unit()));
+        assert!(units.contains(&md1.unit()));
+        assert!(units.is_sorted());
+    }
+
+    #[test]
+    fn list_info() {
+        let md = Builder::swap(1 << 20).label("list_info").create().unwrap();
+
+        let info = mdconfig::list_info()
+            .unwrap()
+            .into_iter()
+            .find(|i| i.unit == md.unit())
+            .unwrap();
+        assert_eq!(info.md_type, MdType::Swap);
+        assert_eq!(info.label.as_deref(), Some("list_info"));
+    }
+}
+
+mod owner {
+    use super::*;
+
+    /// An owner tag for a process that can't exist, because its pid exceeds PID_MAX
+    const DEAD_TAG: &str = "mdconfig-owner=2147483647,1,-";
+
+    #[test]
+    fn label() {
+        let md = Builder::malloc(1 << 20)
+            .label("foo")
+            .owner_tag(true)
+            .create()
+            .unwrap();
+        let info = md.query().unwrap();
+        assert!(info.label.as_deref().unwrap().starts_with("foo "));
+        assert_eq!(info.owner(), Some(OwnerTag::current()));
+    }
+
+    /// Devices created by dead processes are reaped.  Others are left alone.
+    #[test]
+    fn reap_orphans() {
+        let orphan = Builder::malloc(1 << 20)
+            .label(DEAD_TAG)
+            .drop_policy(DropPolicy::Leak)
+            .create()
+            .unwrap();
+        let mine = Builder::malloc(1 << 20).owner_tag(true).create().unwrap();
+        let reaped = mdconfig::reap_orphans().unwrap();
+        assert!(reaped.iter().any(|info| info.unit == orphan.unit()));
+        assert!(!reaped.iter().any(|info| info.unit == mine.unit()));
+        mine.query().unwrap();
+        assert!(matches!(orphan.query(), Err(MdError::NoSuchUnit(_))));
+    }
+
+    /// Orphans are reaped even when there are too many devices for MDIOCLIST to report.
+    #[test]
+    fn reap_orphans_truncated() {
+        // The kernel lists the newest devices first, so create the orphan before the rest to
+        // ensure that MDIOCLIST omits it.
+        let orphan = Builder::null(1 << 20)
+            .label(DEAD_TAG)
+            .drop_policy(DropPolicy::Leak)
+            .create()
+            .unwrap();
+        // MDIOCLIST reports at most 94 units.
+        let mine = (0..100)
+            .map(|_| Builder::null(1 << 20).owner_tag(true).create().unwrap())
+            .collect::<Vec<_>>();
+        let reaped = mdconfig::reap_orphans().unwrap();
+        assert!(reaped.iter().any(|info| info.unit == orphan.unit()));
+        assert!(
+            !reaped
+                .iter()
+                .any(|info| mine.iter().any(|md| md.unit() == info.unit))
+        );
+        assert!(matches!(orphan.query(), Err(MdError::NoSuchUnit(_))));
+    }
+}
+
+mod persistent {
+    use super::*;
+
+    #[test]
+    fn mismatch() {
+        let md = Builder::swap(1 << 20).create().unwrap();
+        let token = md.into_persistent().unwrap();
+        let md = Md::from_unit(token.unit()).unwrap();
+        md.resize(1 << 21, false).unwrap();
+
+        let e = Md::reclaim(&token).unwrap_err();
+        assert_eq!(e.operation(), Operation::Reclaim);
+        assert_eq!(e.unit(), Some(token.unit()));
+    }
+
+    #[test]
+    fn reclaim() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        tf.as_file().set_len(1 << 21).unwrap();
+        let md = Builder::vnode(tf.path())
+            .label("persistent label")
+            .create()
+            .unwrap();
+        let unit = md.unit();
+        let saved = md.into_persistent().unwrap().to_string();
+        assert!(mdconfig::list().unwrap().contains(&unit));
+
+        let token = saved.parse::<MdToken>().unwrap();
+        assert_eq!(token.unit(), unit);
+        assert_eq!(token.label(), Some("persistent label"));
+        assert_eq!(token.info().file.as_deref(), Some(tf.path()));
+        let md = Md::reclaim(&token).unwrap();
+        drop(md);
+        assert!(!mdconfig::list().unwrap().contains(&unit));
+    }
+}
+
+mod preloaded {
+    use super::*;
+
+    #[test]
+    fn list() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+
+        let preloaded = mdconfig::preloaded().unwrap();
+        assert!(
+            preloaded
+                .iter()
+                .all(|p| p.info().md_type == MdType::Preload)
+        );
+        assert!(!preloaded.iter().any(|p| p.unit() == md.unit()));
+    }
+
+    #[test]
+    fn not_preloaded() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+
+        let e = PreloadedMd::from_unit(md.unit()).unwrap_err();
+        assert!(matches!(e, MdError::WrongType(_)));
+    }
+
+    /// Preloaded devices may be inspected, but never owned.  This test can only do anything on
+    /// a system that was booted with a preloaded md.
+    #[test]
+    fn not_owned() {
+        for p in mdconfig::preloaded().unwrap() {
+            assert!(p.size() > 0);
+            assert_eq!(p.size(), p.query().unwrap().mediasize);
+            let e = Md::from_unit(p.unit()).unwrap_err();
+            assert!(matches!(e, MdError::WrongType(_)));
+            assert_eq!(e.operation(), Operation::Own);
+            drop(p);
+        }
+    }
+}
+
+mod query {
+    use super::*;
+
+    #[test]
+    fn enoent() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        let unit = md.unit();
+        drop(md);
+        let e = mdconfig::query(unit).unwrap_err();
+        assert!(matches!(e, MdError::NoSuchUnit(_)));
+        assert_eq!(libc::ENOENT, e.raw_os_error().unwrap());
+    }
+
+    #[test]
+    fn label() {
+        let md = Builder::null(1 << 20).label("foo").create().unwrap();
+
+        let info = md.query().unwrap();
+        assert_eq!(info.label.as_deref(), Some("foo"));
+    }
+
+    #[test]
+    fn malloc() {
+        let md = Builder::malloc(1 << 20).sectorsize(4096).create().unwrap();
+
+        let info = md.query().unwrap();
+        assert_eq!(info.unit, md.unit());
+        assert_eq!(info.md_type, MdType::Malloc);
+        assert_eq!(info.mediasize, 1 << 20);
+        assert_eq!(info.sectorsize, 4096);
+        assert_eq!(info.file, None);
+        assert_eq!(info.label, None);
+    }
+
+    #[test]
+    fn vnode() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        tf.as_file().set_len(1 << 21).unwrap();
+        let md = Builder::vnode(tf.path()).create().unwrap();
+
+        let info = mdconfig::query(md.unit()).unwrap();
+        assert_eq!(info.md_type, MdType::Vnode);
+        assert_eq!(info.mediasize, 1 << 21);
+        assert_eq!(info.file.as_deref(), Some(tf.path()));
+    }
+}
+
+mod registry {
+    use std::{io::Write, process::ExitStatus};
+
+    use super::*;
+
+    const CHILD: &str = "MDCONFIG_REGISTRY_CHILD";
+
+    /// Run one of the ignored tests below in a subprocess.  Return its exit status and the unit
+    /// number it created.
+    fn run_child(name: &str) -> (ExitStatus, u32) {
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", name, "--ignored", "--nocapture"])
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let unit = stdout
+            .lines()
+            .find_map(|l| l.strip_prefix("unit="))
+            .unwrap()
+            .parse()
+            .unwrap();
+        (output.status, unit)
+    }
+
+    /// Create a registered device, print its unit number, and never drop it.
+    fn create_registered() -> Md {
+        install_signal_handlers().unwrap();
+        let md = Builder::null(1 << 20).create().unwrap();
+        println!("unit={}", md.unit());
+        std::io::stdout().flush().unwrap();
+        md
+    }
+
+    #[test]
+    #[ignore = "run by registry::exit"]
+    fn exit_child() {
+        if std::env::var_os(CHILD).is_none() {
+            return;
+        }
+        let _md = create_registered();
+        std::process::exit(0);
+    }
+
+    /// Registered devices are detached by process::exit
+    #[test]
+    fn exit() {
+        let (status, unit) = run_child("registry::exit_child");
+        assert!(status.success());
+        assert!(matches!(query(unit), Err(MdError::NoSuchUnit(_))));
+    }
+
+    #[test]
+    #[ignore = "run by registry::reused_unit"]
+    fn reused_unit_child() {
+        if std::env::var_os(CHILD).is_none() {
+            return;
+        }
+        let old = create_registered();
+        detach_registered();
+        let new = Builder::null(1 << 20).unit(old.unit()).create().unwrap();
+        // Dropping the old Md must not detach the new device, nor deregister it.
+        drop(old);
+        new.query().unwrap();
+        std::mem::forget(new);
+        std::process::exit(0);
+    }
+
+    /// After detach_registered, a new device may reuse the unit number of an old one
+    #[test]
+    fn reused_unit() {
+        let (status, unit) = run_child("registry::reused_unit_child");
+        assert!(status.success());
+        // The new device was still registered, so exit detached it.
+        assert!(matches!(query(unit), Err(MdError::NoSuchUnit(_))));
+    }
+
+    #[test]
+    #[ignore = "run by registry::sigterm"]
+    fn sigterm_child() {
+        if std::env::var_os(CHILD).is_none() {
+            return;
+        }
+        let _md = create_registered();
+        unsafe { libc::raise(libc::SIGTERM) };
+        unreachable!();
+    }
+
+    /// Registered devices are detached by fatal signals
+    #[test]
+    fn sigterm() {
+        let (status, unit) = run_child("registry::sigterm_child");
+        assert!(!status.success());
+        assert!(matches!(query(unit), Err(MdError::NoSuchUnit(_))));
+    }
+}
+
+mod resize {
+    use super::*;
+
+    #[test]
+    fn down() {
+        let md = Builder::swap(1 << 21).create().unwrap();
+
+        let data = list_unit(md.unit());
+        assert_eq!(data.size, "2048K");
+
+        md.resize(1 << 20, true).unwrap();
+        let data = list_unit(md.unit());
+        assert_eq!(data.size, "1024K");
+    }
+
+    #[test]
+    fn needs_force() {
+        let md = Builder::swap(1 << 21).create().unwrap();
+
+        let e = md.resize(1 << 20, false).unwrap_err();
+        assert!(matches!(e, MdError::ResizeNeedsForce(_)));
+        assert_eq!(e.operation(), Operation::Resize);
+        assert_eq!(e.unit(), Some(md.unit()));
+    }
+
+    #[test]
+    fn up() {
+        let md = Builder::swap(1 << 20).create().unwrap();
+
+        let data = list_unit(md.unit());
+        assert_eq!(data.size, "1024K");
+
+        md.resize(1 << 21, false).unwrap();
+        let data = list_unit(md.unit());
+        assert_eq!(data.size, "2048K");
+    }
+
+    /// Growth is rounded up to a whole number of sectors
+    #[test]
+    fn grow_by() {
+        let md = Builder::null(1 << 20).sectorsize(4096).create().unwrap();
+        assert_eq!(
+            md.grow_by(1, FileGrowth::Sparse).unwrap(),
+            (1 << 20) + 4096
+        );
+        assert_eq!(md.query().unwrap().mediasize, (1 << 20) + 4096);
+    }
+
+    /// Swap-backed devices' growth is rounded up to a whole number of pages
+    #[test]
+    fn grow_by_swap() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let md = Builder::swap(1 << 20).create().unwrap();
+        assert_eq!(
+            md.grow_by(1, FileGrowth::Sparse).unwrap(),
+            (1 << 20) + page_size
+        );
+    }
+
+    #[test]
+    fn not_multiple_of_sectorsize() {
+        let md = Builder::malloc(1 << 20).sectorsize(4096).create().unwrap();
+        let e = md
+            .resize_with_file(3 << 19 | 512, false, FileGrowth::Sparse)
+            .unwrap_err();
+        let MdError::InvalidConfig(_, problems) = e else {
+            panic!("Unexpected error {e}");
+        };
+        assert!(matches!(
+            problems[..],
+            [ConfigProblem::SizeNotMultipleOfSectorsize { .. }]
+        ));
+    }
+
+    #[test]
+    fn shrink_by() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        assert_eq!(md.shrink_by(1000, true).unwrap(), (1 << 20) - 1024);
+    }
+
+    /// Shrinking by the whole size or more is an error, not a resize to 0
+    #[test]
+    fn shrink_by_too_much() {
+        let md = Builder::null(1 << 20).create().unwrap();
+        for bytes in [1 << 20, 1 << 21] {
+            let e = md.shrink_by(bytes, true).unwrap_err();
+            let MdError::InvalidConfig(_, problems) = e else {
+                panic!("Unexpected error {e}");
+            };
+            assert!(matches!(
+                problems[..],
+                [ConfigProblem::SizeNotPositive(_)]
+            ));
+        }
+        assert_eq!(md.query().unwrap().mediasize, 1 << 20);
+    }
+
+    /// Shrinking without force fails before the backing file is touched
+    #[test]
+    fn shrink_by_needs_force() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        tf.as_file().set_len(1 << 21).unwrap();
+        let md = Builder::vnode(tf.path()).create().unwrap();
+
+        let e = md.shrink_by(1 << 20, false).unwrap_err();
+        assert!(matches!(e, MdError::ResizeNeedsForce(_)));
+        assert_eq!(tf.as_file().metadata().unwrap().len(), 1 << 21);
+    }
+
+    #[test]
+    fn with_file_down() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        tf.as_file().set_len(1 << 21).unwrap();
+        let md = Builder::vnode(tf.path()).create().unwrap();
+
+        md.resize_with_file(1 << 20, true, FileGrowth::Sparse)
+            .unwrap();
+        assert_eq!(md.query().unwrap().mediasize, 1 << 20);
+        assert_eq!(tf.as_file().metadata().unwrap().len(), 1 << 20);
+    }
+
+    #[test]
+    fn with_file_preallocate() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        tf.as_file().set_len(1 << 20).unwrap();
+        let md = Builder::vnode(tf.path()).create().unwrap();
+
+        md.resize_with_file(1 << 21, false, FileGrowth::Preallocate)
+            .unwrap();
+        assert_eq!(md.query().unwrap().mediasize, 1 << 21);
+        assert_eq!(tf.as_file().metadata().unwrap().len(), 1 << 21);
+    }
+
+    #[test]
+    fn with_file_up() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        tf.as_file().set_len(1 << 20).unwrap();
+        let md = Builder::vnode(tf.path()).create().unwrap();
+
+        md.resize_with_file(1 << 21, false, FileGrowth::Sparse)
+            .unwrap();
+        assert_eq!(md.query().unwrap().mediasize, 1 << 21);
+        assert_eq!(tf.as_file().metadata().unwrap().len(), 1 << 21);
+    }
+}
+
+mod set {
+    use super::*;
+
+    #[test]
+    fn create() {
+        let set =
+            MdSet::create((0..3).map(|_| Builder::null(1 << 20))).unwrap();
+        assert_eq!(set.len(), 3);
+        let paths = set.paths().collect::<Vec<_>>();
+        for (md, path) in set.iter().zip(paths) {
+            assert_eq!(md.path(), path);
+            assert!(fs::metadata(path).unwrap().file_type().is_char_device());
+        }
+    }
+
+    /// Nothing is created if any Builder is invalid
+    #[test]
+    fn invalid() {
+        let builders =
+            vec![Builder::null(1 << 20), Builder::swap(1 << 20).cache(true)];
+        let e = MdSet::create(builders).unwrap_err();
+        assert!(matches!(e, MdError::InvalidConfig(..)));
+    }
+
+    /// If one device can't be created, the others are detached
+    #[test]
+    fn rollback() {
+        let md = Builder::null(1 << 20).unit(710).create().unwrap();
+        let builders = vec![
+            Builder::null(1 << 20).unit(711),
+            Builder::null(1 << 20).unit(710),
+        ];
+        let e = MdSet::create(builders).unwrap_err();
+        assert!(matches!(e, MdError::UnitInUse(_)));
+        assert!(matches!(query(711), Err(MdError::NoSuchUnit(_))));
+        md.query().unwrap();
+    }
+
+    #[test]
+    fn try_destroy() {
+        let set = MdSet::create(
+            (0..2).map(|_| Builder::swap(1 << 21).wait_ready(true)),
+        )
+        .unwrap();
+        let units = set.iter().map(Md::unit).collect::<Vec<_>>();
+        set.try_destroy().unwrap();
+        for unit in units {
+            assert!(matches!(query(unit), Err(MdError::NoSuchUnit(_))));
+        }
+    }
+
+    /// On failure, the devices that remain are returned
+    #[test]
+    fn try_destroy_busy() {
+        let set = MdSet::create(
+            (0..2).map(|_| Builder::swap(1 << 21).wait_ready(true)),
+        )
+        .unwrap();
+        let _f = fs::File::open(set[0].path()).unwrap();
+        let (set, e) = set.try_destroy().unwrap_err();
+        assert!(matches!(e, MdError::Busy(_)));
+        assert_eq!(set.len(), 1);
+    }
+}
+
+mod shape {
+    use super::*;
+
+    #[test]
+    fn from_unit() {
+        let md = Builder::swap(1 << 20).sectorsize(4096).create().unwrap();
+        let md = MdRef::from_unit(md.unit()).unwrap();
+        assert_eq!(md.size(), 1 << 20);
+        assert_eq!(md.sectorsize(), 4096);
+        assert_eq!(md.md_type(), MdType::Swap);
+    }
+
+    #[test]
+    fn malloc() {
+        let md = Builder::malloc(1 << 20).create().unwrap();
+        assert_eq!(md.size(), 1 << 20);
+        assert_eq!(md.sectorsize(), 512);
+        assert_eq!(md.md_type(), MdType::Malloc);
+        assert!(md.options().contains(MdOptions::COMPRESS));
+        assert_eq!(md.backing_file(), None);
+    }
+
+    /// The size is kept current across resizes
+    #[test]
+    fn resize() {
+        let md = Builder::swap(1 << 20).create().unwrap();
+        md.resize(1 << 21, false).unwrap();
+        assert_eq!(md.size(), 1 << 21);
+        md.shrink_by(1 << 20, true).unwrap();
+        assert_eq!(md.size(), 1 << 20);
+    }
+
+    /// The size of a vnode-backed device defaults to the size of the file
+    #[test]
+    fn vnode() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        tf.as_file().set_len(1 << 21).unwrap();
+        let md = Builder::vnode(tf.path()).create().unwrap();
+        assert_eq!(md.size(), 1 << 21);
+        assert_eq!(md.md_type(), MdType::Vnode);
+        assert_eq!(md.backing_file(), Some(tf.path()));
+    }
+}
+
+mod try_destroy {
+    use std::{
+        thread::sleep,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[test]
+    fn ebusy() {
+        let md = Builder::swap(1 << 21).create().unwrap();
+        let _f = fs::File::open(md.path()).unwrap();
+        let (_md, e) = md.try_destroy().unwrap_err();
+        assert_eq!(libc::EBUSY, e.raw_os_error().unwrap());
+        assert!(matches!(e, MdError::Busy(_)));
+    }
+
+    #[test]
+    fn ok() {
+        let mut md = Builder::swap(1 << 21).create().unwrap();
+        let timeout = Duration::from_secs(5);
         let start = Instant::now();
         loop {
             md = match md.try_destroy() {
@@ -401,3 +1619,67 @@ mod try_destroy {
         }
     }
 }
+
+mod verify {
+    use super::*;
+
+    #[test]
+    fn md() {
+        let md = Builder::malloc(1 << 20).sectorsize(2048).create().unwrap();
+        let disk = md
+            .open(fs::OpenOptions::new().read(true).write(true))
+            .unwrap();
+        let lbas = 0..md.size() / 2048;
+        let v = Verifier::new(0xdead_beef, md.sectorsize());
+        v.fill(&disk, lbas.clone()).unwrap();
+        let report = v.verify(&disk, lbas).unwrap();
+        assert!(report.is_ok(), "{report}");
+    }
+
+    /// The pattern survives detaching and re-attaching a vnode-backed device
+    #[test]
+    fn reattach() {
+        let tf = tempfile::NamedTempFile::new().unwrap();
+        tf.as_file().set_len(1 << 20).unwrap();
+        let v = Verifier::new(42, 512);
+        {
+            let md = Builder::vnode(tf.path()).create().unwrap();
+            let disk = md.open(fs::OpenOptions::new().write(true)).unwrap();
+            v.fill(&disk, 0..2048).unwrap();
+        }
+        let md = Builder::vnode(tf.path()).create().unwrap();
+        let disk = md.open(fs::OpenOptions::new().read(true)).unwrap();
+        let report = v.verify(&disk, 0..2048).unwrap();
+        assert!(report.is_ok(), "{report}");
+    }
+}
+
+mod wait_ready {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Once ready, a device can be detached without force
+    #[test]
+    fn builder() {
+        let md = Builder::swap(1 << 21).wait_ready(true).create().unwrap();
+        md.try_destroy().unwrap();
+    }
+
+    #[test]
+    fn md() {
+        let md = Builder::swap(1 << 21).create().unwrap();
+        md.wait_ready(Duration::from_secs(10)).unwrap();
+        md.try_destroy().unwrap();
+    }
+
+    /// A device that's held open never becomes ready
+    #[test]
+    fn timeout() {
+        let md = Builder::swap(1 << 21).create().unwrap();
+        let _f = fs::File::open(md.path()).unwrap();
+        let e = md.wait_ready(Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(e, MdError::TimedOut(_)));
+        assert_eq!(e.operation(), Operation::Wait);
+    }
+}